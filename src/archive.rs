@@ -0,0 +1,320 @@
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context as _;
+use url::Url;
+
+use crate::config::{ArchiveConfig, ArchiveLayout};
+use crate::format_duration;
+use crate::nfo;
+use crate::publisher::{Files, Publisher, Receipt};
+use crate::scrape::{Scraperesult, Topic};
+use crate::wdr_media::{CaptionFormat, TrackerData};
+
+/// Keeps the finished files in a local directory
+///
+/// See [`ArchiveLayout`] for the layouts.
+pub struct Archive {
+    directory: PathBuf,
+    layout: ArchiveLayout,
+}
+
+/// Where the files of a video end up
+struct Placement {
+    directory: PathBuf,
+    stem: String,
+    /// Appended to the stem of the sign language video
+    sl_suffix: &'static str,
+    /// Appended to the stem of the audio description video
+    ad_suffix: &'static str,
+    /// Appended to the stem of the cover
+    cover_suffix: &'static str,
+    /// Appended to the stem of the subtitles
+    subtitle_suffix: &'static str,
+}
+
+impl Archive {
+    pub fn new(config: &ArchiveConfig) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&config.directory).with_context(|| {
+            format!(
+                "failed to create archive directory {}",
+                config.directory.display()
+            )
+        })?;
+        println!(
+            "Archive stores into {} ({:?})",
+            config.directory.display(),
+            config.layout
+        );
+        Ok(Self {
+            directory: config.directory.clone(),
+            layout: config.layout,
+        })
+    }
+
+    /// Copy the files into the archive. Returns the path of the normal video.
+    pub fn store(
+        &self,
+        topic: Topic,
+        tracker_data: &TrackerData,
+        files: &Files,
+    ) -> anyhow::Result<PathBuf> {
+        let placement = match self.layout {
+            ArchiveLayout::Flat => self.flat(topic, tracker_data),
+            ArchiveLayout::MediaServer => self.media_server(topic, tracker_data),
+        };
+        let Placement {
+            directory,
+            stem,
+            sl_suffix,
+            ad_suffix,
+            cover_suffix,
+            subtitle_suffix,
+        } = &placement;
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("failed to create {}", directory.display()))?;
+
+        let normal = directory.join(format!("{stem}.mp4"));
+        copy(files.normal, &normal)?;
+        if let Some(sl) = files.sl {
+            copy(sl, &directory.join(format!("{stem}{sl_suffix}.mp4")))?;
+        }
+        if let Some(ad) = files.ad {
+            copy(ad, &directory.join(format!("{stem}{ad_suffix}.mp4")))?;
+        }
+        copy(
+            files.cover,
+            &directory.join(format!("{stem}{cover_suffix}.jpg")),
+        )?;
+        for (format, url) in files.captions.available() {
+            let extension = format.extension();
+            let path = directory.join(format!("{stem}{subtitle_suffix}{extension}"));
+            // The video itself is archived, missing captions are not worth failing for
+            if let Err(error) = download(url, &path) {
+                eprintln!("failed to archive caption {url}: {error:#}");
+            }
+        }
+        let has_srt = files
+            .captions
+            .available()
+            .any(|(format, _)| format == CaptionFormat::Srt);
+        if let Some(subtitle) = files.subtitle
+            && subtitle.source != subtitle.format
+            && !has_srt
+        {
+            // Only EBU-TT was available which media players rarely understand
+            let extension = subtitle.format.extension();
+            copy(
+                subtitle.file.path(),
+                &directory.join(format!("{stem}{subtitle_suffix}{extension}")),
+            )?;
+        }
+
+        if self.layout == ArchiveLayout::MediaServer {
+            self.write_media_server_metadata(topic, tracker_data, files.cover, &placement)?;
+        }
+        Ok(normal)
+    }
+
+    fn flat(&self, topic: Topic, tracker_data: &TrackerData) -> Placement {
+        Placement {
+            directory: self.directory.join(topic.to_string()),
+            stem: file_stem(tracker_data),
+            sl_suffix: " (DGS)",
+            ad_suffix: " (AD)",
+            cover_suffix: "",
+            subtitle_suffix: "",
+        }
+    }
+
+    fn media_server(&self, topic: Topic, tracker_data: &TrackerData) -> Placement {
+        let show = nfo::show_title(topic);
+        let title = sanitize(&tracker_data.title);
+        let (season, stem) = nfo::EpisodeNumber::new(tracker_data).map_or_else(
+            // Jellyfin and Kodi treat this as season 0
+            || ("Specials".to_owned(), format!("{show} - {title}")),
+            |number| {
+                (
+                    format!("Season {}", number.season),
+                    format!("{show} - S{}E{} - {title}", number.season, number.episode),
+                )
+            },
+        );
+        Placement {
+            directory: self.directory.join(show).join(season),
+            stem,
+            // Jellyfin groups `<stem> - <label>.mp4` as versions of the same episode
+            sl_suffix: " - DGS",
+            ad_suffix: " - AD",
+            cover_suffix: "-thumb",
+            subtitle_suffix: ".de",
+        }
+    }
+
+    fn write_media_server_metadata(
+        &self,
+        topic: Topic,
+        tracker_data: &TrackerData,
+        cover: &Path,
+        placement: &Placement,
+    ) -> anyhow::Result<()> {
+        let show_directory = self.directory.join(nfo::show_title(topic));
+        write_if_missing(&show_directory.join("tvshow.nfo"), &nfo::tvshow(topic))?;
+        for image in ["poster.jpg", "fanart.jpg"] {
+            copy_if_missing(cover, &show_directory.join(image))?;
+        }
+        copy_if_missing(cover, &placement.directory.join("poster.jpg"))?;
+
+        let episode = placement.directory.join(format!("{}.nfo", placement.stem));
+        std::fs::write(&episode, nfo::episode(topic, tracker_data))
+            .with_context(|| format!("failed to write {}", episode.display()))?;
+        Ok(())
+    }
+}
+
+impl Publisher for Archive {
+    fn name(&self) -> &'static str {
+        "archive"
+    }
+
+    fn finished(&self, video: &Scraperesult, files: &Files) -> anyhow::Result<Receipt> {
+        let start = Instant::now();
+        let path = self.store(video.topic, &video.media.tracker_data, files)?;
+        println!(
+            "archive  took {}  {}",
+            format_duration(start.elapsed()),
+            path.display()
+        );
+        Ok(Receipt::default())
+    }
+}
+
+fn copy_if_missing(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if to.exists() {
+        return Ok(());
+    }
+    copy(from, to)
+}
+
+fn write_if_missing(path: &Path, content: &str) -> anyhow::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    std::fs::write(path, content).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Copy via a temporary name so an interrupted copy never looks like a finished file
+fn copy(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let partial = to.with_extension("part");
+    std::fs::copy(from, &partial)
+        .with_context(|| format!("failed to copy {} to {}", from.display(), to.display()))?;
+    std::fs::rename(&partial, to)?;
+    Ok(())
+}
+
+fn download(url: &Url, to: &Path) -> anyhow::Result<()> {
+    let mut reader = ureq::get(url.as_str()).call()?.into_body().into_reader();
+    let partial = to.with_extension("part");
+    {
+        let file = std::fs::File::create(&partial)?;
+        let mut writer = BufWriter::new(file);
+        std::io::copy(&mut reader, &mut writer)?;
+    }
+    std::fs::rename(&partial, to)?;
+    Ok(())
+}
+
+fn file_stem(tracker_data: &TrackerData) -> String {
+    let title = sanitize(&tracker_data.title);
+    match tracker_data.air_date() {
+        Some(date) => format!("{date} - {title}"),
+        None => title,
+    }
+}
+
+/// Replace characters which are not allowed or annoying in file names
+fn sanitize(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|char| match char {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            char if char.is_control() => '_',
+            char => char,
+        })
+        .collect::<String>();
+    let name = name.trim().trim_start_matches('.').trim();
+    if name.is_empty() {
+        "_".to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+#[test]
+fn sanitize_works() {
+    assert_eq!(
+        sanitize("Die Sendung vom 21.11.2021"),
+        "Die Sendung vom 21.11.2021"
+    );
+    assert_eq!(sanitize("Was ist 1/2?"), "Was ist 1_2_");
+    assert_eq!(sanitize(" ../etc "), "_etc");
+    assert_eq!(sanitize(""), "_");
+}
+
+#[test]
+fn file_stem_works() {
+    let with_date = TrackerData {
+        id: "mdb-2580812".into(),
+        air_time: Some("21.11.2021 09:30".into()),
+        title: "Die Sendung vom 21.11.2021".into(),
+    };
+    assert_eq!(
+        file_stem(&with_date),
+        "2021-11-21 - Die Sendung vom 21.11.2021"
+    );
+
+    let without_date = TrackerData {
+        id: "mdb-1407836".into(),
+        air_time: None,
+        title: "Lenas Sommer auf der Alpe".into(),
+    };
+    assert_eq!(file_stem(&without_date), "Lenas Sommer auf der Alpe");
+}
+
+#[test]
+fn media_server_placement() {
+    let archive = Archive {
+        directory: "/archive".into(),
+        layout: ArchiveLayout::MediaServer,
+    };
+    let with_date = TrackerData {
+        id: "mdb-2580812".into(),
+        air_time: Some("21.11.2021 09:30".into()),
+        title: "Die Sendung vom 21.11.2021".into(),
+    };
+    let placement = archive.media_server(Topic::AktuelleSendung, &with_date);
+    assert_eq!(
+        placement.directory,
+        Path::new("/archive/Die Sendung mit der Maus/Season 2021")
+    );
+    assert_eq!(
+        placement.stem,
+        "Die Sendung mit der Maus - S2021E2580812 - Die Sendung vom 21.11.2021"
+    );
+
+    let without_date = TrackerData {
+        id: "mdb-1407836".into(),
+        air_time: None,
+        title: "Lenas Sommer auf der Alpe".into(),
+    };
+    let placement = archive.media_server(Topic::Sachgeschichte, &without_date);
+    assert_eq!(
+        placement.directory,
+        Path::new("/archive/Sachgeschichten/Specials")
+    );
+    assert_eq!(
+        placement.stem,
+        "Sachgeschichten - Lenas Sommer auf der Alpe"
+    );
+}
@@ -0,0 +1,188 @@
+use std::fmt::Write as _;
+
+use anyhow::Context as _;
+use lazy_regex::regex;
+use tempfile::NamedTempFile;
+
+use crate::temporary::get_tempfile;
+use crate::wdr_media::{CaptionFormat, Captions};
+
+/// Local subtitle file which ffmpeg can read
+pub struct Subtitle {
+    /// Format of the original file at WDR
+    pub source: CaptionFormat,
+    /// Format of the local file. EBU-TT is converted to SRT.
+    pub format: CaptionFormat,
+    pub file: NamedTempFile,
+}
+
+/// Download the first usable caption format and convert it when ffmpeg cant read it
+pub fn prepare(captions: &Captions) -> Option<Subtitle> {
+    for (source, url) in captions.available() {
+        let result = ureq::get(url.as_str())
+            .call()
+            .and_then(|response| response.into_body().read_to_string())
+            .context("download captions")
+            .and_then(|content| match source {
+                CaptionFormat::Srt | CaptionFormat::Vtt => Ok((source, content)),
+                CaptionFormat::EbuTt => Ok((CaptionFormat::Srt, ebu_tt_to_srt(&content)?)),
+            })
+            .and_then(|(format, content)| {
+                let file = get_tempfile(format.extension())?;
+                std::fs::write(file.path(), content)?;
+                Ok(Subtitle {
+                    source,
+                    format,
+                    file,
+                })
+            });
+        match result {
+            Ok(subtitle) => return Some(subtitle),
+            Err(error) => println!("Ignore {source:?} caption {url}: {error:#}"),
+        }
+    }
+    None
+}
+
+/// Convert EBU-TT (TTML) like provided in `captionsHash.xml` into SRT
+pub fn ebu_tt_to_srt(xml: &str) -> anyhow::Result<String> {
+    let mut srt = String::new();
+    let mut index = 0;
+    for captures in regex!(r"(?s)<(?:tt:)?p\b([^>]*)>(.*?)</(?:tt:)?p>").captures_iter(xml) {
+        let attributes = &captures[1];
+        let begin = regex!(r#"\bbegin="([^"]+)""#)
+            .captures(attributes)
+            .context("caption without begin")?;
+        let end = regex!(r#"\bend="([^"]+)""#)
+            .captures(attributes)
+            .context("caption without end")?;
+        let begin = parse_time(&begin[1])?;
+        let end = parse_time(&end[1])?;
+
+        let text = regex!(r"<(?:tt:)?br\s*/>").replace_all(&captures[2], "\n");
+        let text = regex!(r"<[^>]+>").replace_all(&text, "");
+        let text = unescape(&text);
+        let text = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if text.is_empty() {
+            continue;
+        }
+
+        index += 1;
+        writeln!(
+            srt,
+            "{index}\n{} --> {}\n{text}\n",
+            format_srt_time(begin),
+            format_srt_time(end)
+        )
+        .unwrap();
+    }
+    anyhow::ensure!(index > 0, "no captions found in EBU-TT");
+    Ok(srt)
+}
+
+/// Parse TTML time expressions into milliseconds
+fn parse_time(time: &str) -> anyhow::Result<u64> {
+    /// EBU-TT-D from WDR uses 25 frames per second when frames are given
+    const FRAMES_PER_SECOND: u64 = 25;
+
+    if let Some(captures) = regex!(r"^(\d+):(\d{2}):(\d{2})(?:\.(\d+)|:(\d{2}))?$").captures(time) {
+        let hours = captures[1].parse::<u64>()?;
+        let minutes = captures[2].parse::<u64>()?;
+        let seconds = captures[3].parse::<u64>()?;
+        let millis = if let Some(fraction) = captures.get(4) {
+            // Only the first three digits are relevant for milliseconds
+            let fraction = format!("{:0<3}", fraction.as_str());
+            #[expect(clippy::string_slice)]
+            fraction[..3].parse::<u64>()?
+        } else if let Some(frames) = captures.get(5) {
+            frames.as_str().parse::<u64>()? * 1000 / FRAMES_PER_SECOND
+        } else {
+            0
+        };
+        return Ok((((hours * 60) + minutes) * 60 + seconds) * 1000 + millis);
+    }
+
+    if let Some(captures) = regex!(r"^(\d+(?:\.\d+)?)(h|m|s|ms)$").captures(time) {
+        let value = captures[1].parse::<f64>()?;
+        let factor = match &captures[2] {
+            "h" => 3_600_000.0,
+            "m" => 60_000.0,
+            "s" => 1000.0,
+            _ => 1.0,
+        };
+        #[expect(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        return Ok((value * factor).round() as u64);
+    }
+
+    anyhow::bail!("unknown time expression {time:?}")
+}
+
+fn format_srt_time(millis: u64) -> String {
+    let hours = millis / 3_600_000;
+    let minutes = (millis / 60_000) % 60;
+    let seconds = (millis / 1000) % 60;
+    let millis = millis % 1000;
+    format!("{hours:02}:{minutes:02}:{seconds:02},{millis:03}")
+}
+
+fn unescape(text: &str) -> String {
+    let text =
+        regex!(r"&#(x?)([0-9a-fA-F]+);").replace_all(text, |captures: &lazy_regex::Captures| {
+            let radix = if captures[1].is_empty() { 10 } else { 16 };
+            u32::from_str_radix(&captures[2], radix)
+                .ok()
+                .and_then(char::from_u32)
+                .map(String::from)
+                .unwrap_or_default()
+        });
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[test]
+fn parse_time_works() {
+    assert_eq!(parse_time("00:00:02.000").unwrap(), 2000);
+    assert_eq!(parse_time("00:01:02.5").unwrap(), 62_500);
+    assert_eq!(parse_time("01:00:00.1234").unwrap(), 3_600_123);
+    assert_eq!(parse_time("00:00:01:05").unwrap(), 1200);
+    assert_eq!(parse_time("12.5s").unwrap(), 12_500);
+    assert_eq!(parse_time("40ms").unwrap(), 40);
+    assert!(parse_time("soon").is_err());
+}
+
+#[test]
+fn ebu_tt_to_srt_works() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tt:tt xmlns:tt="http://www.w3.org/ns/ttml" xml:lang="de">
+  <tt:body>
+    <tt:div>
+      <tt:p xml:id="sub1" begin="00:00:02.000" end="00:00:05.120" style="s1">
+        <tt:span style="textWhite">Hallo, hier ist</tt:span>
+        <tt:br />
+        <tt:span style="textYellow">die Maus &amp; der Elefant.</tt:span>
+      </tt:p>
+      <tt:p xml:id="sub2" begin="00:01:00.000" end="00:01:03.000">
+        <tt:span>&quot;Klack&quot;</tt:span>
+      </tt:p>
+      <tt:p xml:id="sub3" begin="00:01:04.000" end="00:01:05.000"></tt:p>
+    </tt:div>
+  </tt:body>
+</tt:tt>"#;
+    assert_eq!(
+        ebu_tt_to_srt(xml).unwrap(),
+        "1\n00:00:02,000 --> 00:00:05,120\nHallo, hier ist\ndie Maus & der Elefant.\n\n2\n00:01:00,000 --> 00:01:03,000\n\"Klack\"\n\n"
+    );
+}
+
+#[test]
+fn ebu_tt_without_captions_fails() {
+    assert!(ebu_tt_to_srt("<tt:tt><tt:body/></tt:tt>").is_err());
+}
@@ -0,0 +1,78 @@
+use clap::{Parser, Subcommand};
+use url::Url;
+
+use crate::scrape::{Source, Topic};
+
+#[derive(Debug, Parser)]
+#[command(about, version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check the schedule every five minutes and run the due jobs (default)
+    Run,
+
+    /// Run the currently due job once and exit
+    Once,
+
+    /// Scrape a source regardless of the schedule
+    Scrape {
+        #[arg(value_enum)]
+        source: Source,
+
+        /// Only list the videos which would be downloaded
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Download and publish the videos of a single wdrmaus.de page even when they were downloaded before
+    Download {
+        url: Url,
+
+        #[arg(long, value_enum, default_value_t = Topic::Sachgeschichte)]
+        topic: Topic,
+    },
+
+    /// Work through the Sachgeschichten backlog with separate scrape, download and upload stages
+    CatchUp {
+        /// Maximum amount of videos to download in this run (default: pipeline.quota)
+        #[arg(long)]
+        quota: Option<usize>,
+    },
+
+    /// List the already downloaded videos
+    ListDownloaded,
+
+    /// Show when and how a video was published
+    Show {
+        /// Tracker id like `mdb-2580812`
+        id: String,
+    },
+
+    /// Remove a video from the downloaded list so it gets downloaded again
+    Forget {
+        /// Tracker id like `mdb-2580812`
+        id: String,
+    },
+
+    /// List the videos which failed before and whether they are skipped
+    ListQuarantined,
+
+    /// Release a video from the quarantine so it is tried again
+    Release {
+        /// Tracker id like `mdb-2580812`
+        id: String,
+    },
+
+    /// Check that the external tools can be executed
+    CheckTools,
+}
+
+#[test]
+fn verify_cli() {
+    use clap::CommandFactory as _;
+    Cli::command().debug_assert();
+}
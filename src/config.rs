@@ -0,0 +1,908 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use anyhow::Context as _;
+use chrono::Weekday;
+use serde::Deserialize;
+use url::Url;
+
+use crate::scrape::{Source, Topic};
+
+const CONFIG_PATH: &str = "config.yaml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub telegram: TelegramConfig,
+    pub sources: Sources,
+    pub schedule: Schedule,
+    pub retry: Retry,
+    pub quarantine: QuarantineConfig,
+    pub lock: LockConfig,
+    pub paths: Paths,
+    pub tools: Tools,
+    pub outputs: Outputs,
+    pub quality: Quality,
+    pub encoding: Encoding,
+    pub parallel: Parallel,
+    pub pipeline: Pipeline,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Parallel {
+    /// Variants (normal, DGS, AD) downloaded and encoded at the same time
+    pub variants: usize,
+}
+
+/// Scraping, downloading/encoding and uploading as separate stages working on the backlog
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pipeline {
+    /// Scheduled Sachgeschichten scrapes use the pipeline instead of stopping after the first video
+    pub scheduled: bool,
+    pub scrape_workers: usize,
+    pub download_workers: usize,
+    pub upload_workers: usize,
+    /// Videos waiting between two stages
+    pub queue_size: usize,
+    /// Videos handed to the download stage per run
+    pub quota: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    /// Channel the finished episodes are posted to
+    pub public_channel: i64,
+    /// Channel for progress and error reports
+    pub meta_channel: i64,
+    /// Bigger videos are handled according to [`oversize`](Self::oversize).
+    /// Bots can upload 50 MB with the official api, 2000 MB with a self-hosted one.
+    pub upload_limit_megabytes: u64,
+    pub oversize: Oversize,
+}
+
+/// What to do with videos above the upload limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Oversize {
+    /// Split into numbered parts at keyframes
+    #[default]
+    Split,
+    /// Re-encode to fit the limit
+    Reencode,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sources {
+    pub aktuelle: Url,
+    pub sachgeschichten: Url,
+    pub zukunft: Url,
+}
+
+/// Scrape jobs in the order they are checked. The first due job runs.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct Schedule {
+    pub jobs: Vec<Job>,
+}
+
+/// A scrape which is due while the local time is in its window
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// Completion is remembered per day by this name
+    pub name: String,
+    pub source: Source,
+    /// Days the job runs on. Empty for every day.
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    /// First hour (local time) the job is due
+    #[serde(default)]
+    pub from: u32,
+    /// Hour from which on the job is not due anymore
+    #[serde(default = "default_until")]
+    pub until: u32,
+    /// Run on every check within the window instead of once per day
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+impl Job {
+    pub fn is_due(&self, weekday: Weekday, hour: u32) -> bool {
+        (self.weekdays.is_empty() || self.weekdays.contains(&weekday))
+            && self.from <= hour
+            && hour < self.until
+    }
+}
+
+const fn default_until() -> u32 {
+    24
+}
+
+/// Failed jobs are retried with exponential backoff
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retry {
+    /// Attempts per job and day before it gives up
+    pub max_attempts: u32,
+    /// Wait after the first failure, doubled with every further one
+    pub backoff_minutes: u32,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff_minutes: 10,
+        }
+    }
+}
+
+/// Videos which failed repeatedly are skipped for a while
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuarantineConfig {
+    /// Failures after which a video is skipped
+    pub threshold: u32,
+    /// Days after the last failure until the video is tried again
+    pub days: u32,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            days: 7,
+        }
+    }
+}
+
+/// Only one instance works on the state files at a time
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockConfig {
+    /// Locks of other hosts older than this are considered stale
+    pub stale_hours: u32,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self { stale_hours: 12 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Paths {
+    pub daily: PathBuf,
+    /// `SQLite` database of the downloaded videos
+    pub database: PathBuf,
+    /// Former list of the downloaded videos, migrated into the database once
+    pub downloaded: PathBuf,
+    pub quarantine: PathBuf,
+    /// Held while a job runs
+    pub lock: PathBuf,
+    /// HLS segments are kept here until the video is complete to resume interrupted downloads
+    pub work: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tools {
+    pub nice: PathBuf,
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    pub magick: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Outputs {
+    /// Post to the Telegram channels
+    pub telegram: bool,
+    /// Keep the files in a local directory
+    pub archive: Option<ArchiveConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub layout: ArchiveLayout,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveLayout {
+    /// `<Topic>/<air date> - <title>.mp4`
+    #[default]
+    Flat,
+    /// Jellyfin/Kodi: `<Show>/Season <year>/<Show> - S<year>E<tracker id number> - <title>.mp4` with `.nfo` files
+    MediaServer,
+}
+
+/// Which variant of a HLS stream is downloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Quality {
+    /// Highest bandwidth
+    #[default]
+    Highest,
+    /// Highest bandwidth up to the given video height
+    MaxHeight { height: u32 },
+    /// Highest bandwidth whose estimated file size fits
+    MaxFilesize { megabytes: u64 },
+}
+
+/// Which [`Profile`] is used for which video
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Encoding {
+    /// Profile used when neither the output nor the topic specify one
+    pub default: String,
+    /// Profile per topic
+    pub topics: HashMap<Topic, String>,
+    /// Profile per output (`telegram`, `archive`). Takes precedence over the topic.
+    pub outputs: HashMap<String, String>,
+    /// Additional profiles. `copy`, `h264`, `h265` and `auto` are built in and can be overridden.
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Encoding {
+    pub fn profile_name(&self, output: &str, topic: Topic) -> &str {
+        self.outputs
+            .get(output)
+            .or_else(|| self.topics.get(&topic))
+            .unwrap_or(&self.default)
+    }
+
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        self.profiles.get(name).cloned().or_else(|| match name {
+            "copy" => Some(Profile::Copy),
+            "h264" => Some(Profile::H264 {
+                crf: default_h264_crf(),
+                preset: default_preset(),
+            }),
+            "h265" => Some(Profile::H265 {
+                crf: default_h265_crf(),
+                preset: default_preset(),
+            }),
+            "auto" => Some(Profile::Auto {
+                codecs: default_auto_codecs(),
+                megabytes: None,
+                encode: default_auto_encode(),
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// How the video stream is encoded. Audio and subtitles are always copied.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "codec", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Profile {
+    /// Keep the video stream as provided by WDR
+    Copy,
+    H264 {
+        #[serde(default = "default_h264_crf")]
+        crf: u8,
+        #[serde(default = "default_preset")]
+        preset: String,
+    },
+    H265 {
+        #[serde(default = "default_h265_crf")]
+        crf: u8,
+        #[serde(default = "default_preset")]
+        preset: String,
+    },
+    /// Two-pass H.264 aiming for the given file size
+    TargetSize {
+        megabytes: u64,
+        #[serde(default = "default_preset")]
+        preset: String,
+    },
+    /// Keep the source when it fits, otherwise use another profile
+    Auto {
+        /// Video codecs (as named by ffprobe) which are kept
+        #[serde(default = "default_auto_codecs")]
+        codecs: Vec<String>,
+        /// Sources with a bigger estimated file size are encoded
+        megabytes: Option<u64>,
+        /// Profile used when the source does not fit
+        #[serde(default = "default_auto_encode")]
+        encode: String,
+    },
+}
+
+const fn default_h264_crf() -> u8 {
+    23
+}
+
+const fn default_h265_crf() -> u8 {
+    28
+}
+
+fn default_preset() -> String {
+    "medium".into()
+}
+
+fn default_auto_codecs() -> Vec<String> {
+    vec!["h264".into(), "hevc".into()]
+}
+
+fn default_auto_encode() -> String {
+    "h265".into()
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Self {
+                public_channel: -1_001_149_205_144,
+                meta_channel: -1_001_149_205_144,
+                upload_limit_megabytes: 2000,
+                oversize: Oversize::default(),
+            }
+        } else {
+            Self {
+                public_channel: -1_001_155_474_248,
+                meta_channel: -1_001_214_301_516,
+                upload_limit_megabytes: 2000,
+                oversize: Oversize::default(),
+            }
+        }
+    }
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Self {
+            aktuelle: Url::parse("https://www.wdrmaus.de/aktuelle-sendung/").unwrap(),
+            sachgeschichten: Url::parse(
+                "https://www.wdrmaus.de/filme/sachgeschichten/index.php5?filter=alle",
+            )
+            .unwrap(),
+            zukunft: Url::parse("https://www.wdrmaus.de/extras/mausthemen/zukunft/").unwrap(),
+        }
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self {
+            default: "h265".into(),
+            topics: HashMap::new(),
+            outputs: HashMap::new(),
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+impl Default for Parallel {
+    fn default() -> Self {
+        Self { variants: 2 }
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            scheduled: false,
+            scrape_workers: 1,
+            download_workers: 1,
+            upload_workers: 1,
+            queue_size: 2,
+            quota: 10,
+        }
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        let job = |name: &str, source, from| Job {
+            name: name.into(),
+            source,
+            weekdays: Vec::new(),
+            from,
+            until: default_until(),
+            repeat: false,
+        };
+        Self {
+            jobs: vec![
+                Job {
+                    weekdays: vec![Weekday::Sun],
+                    until: 13,
+                    repeat: true,
+                    ..job("AktuelleSunday", Source::Aktuell, 8)
+                },
+                job("AktuelleCheckup", Source::Aktuell, 19),
+                job("SachgeschichteMorning", Source::Sachgeschichten, 5),
+                job("SachgeschichteEvening", Source::Sachgeschichten, 16),
+            ],
+        }
+    }
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Self {
+            daily: "daily.yaml".into(),
+            database: "downloaded.sqlite".into(),
+            downloaded: "downloaded.yaml".into(),
+            quarantine: "quarantine.yaml".into(),
+            lock: "wdr-maus-downloader.lock".into(),
+            work: "work".into(),
+        }
+    }
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Self {
+            nice: "nice".into(),
+            ffmpeg: "ffmpeg".into(),
+            ffprobe: "ffprobe".into(),
+            magick: "magick".into(),
+        }
+    }
+}
+
+impl Default for Outputs {
+    fn default() -> Self {
+        Self {
+            telegram: true,
+            archive: None,
+        }
+    }
+}
+
+/// Load the config and make it available via [`get`].
+///
+/// The file is taken from `WDR_MAUS_CONFIG` or `config.yaml` in the working directory.
+/// A missing file is fine, then only the defaults and environment overrides are used.
+pub fn init() -> anyhow::Result<&'static Config> {
+    let path =
+        std::env::var_os("WDR_MAUS_CONFIG").map_or_else(|| CONFIG_PATH.into(), PathBuf::from);
+    let mut config = Config::read(&path)?;
+    config.apply_env()?;
+    config
+        .validate()
+        .with_context(|| format!("invalid config {}", path.display()))?;
+    anyhow::ensure!(CONFIG.set(config).is_ok(), "config was already loaded");
+    Ok(get())
+}
+
+/// The config loaded by [`init`] or the defaults when nothing was loaded (tests)
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+impl Config {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content)
+                .with_context(|| format!("config {} format error", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                println!("No config at {}, use defaults", path.display());
+                Ok(Self::default())
+            }
+            Err(err) => {
+                Err(err).with_context(|| format!("failed to read config {}", path.display()))
+            }
+        }
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_yaml::from_str(content)?)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        env_override("WDR_MAUS_PUBLIC_CHANNEL", &mut self.telegram.public_channel)?;
+        env_override("WDR_MAUS_META_CHANNEL", &mut self.telegram.meta_channel)?;
+        env_override("WDR_MAUS_AKTUELLE_URL", &mut self.sources.aktuelle)?;
+        env_override(
+            "WDR_MAUS_SACHGESCHICHTEN_URL",
+            &mut self.sources.sachgeschichten,
+        )?;
+        env_override("WDR_MAUS_ZUKUNFT_URL", &mut self.sources.zukunft)?;
+        env_override("WDR_MAUS_DAILY_PATH", &mut self.paths.daily)?;
+        env_override("WDR_MAUS_DATABASE_PATH", &mut self.paths.database)?;
+        env_override("WDR_MAUS_DOWNLOADED_PATH", &mut self.paths.downloaded)?;
+        env_override("WDR_MAUS_QUARANTINE_PATH", &mut self.paths.quarantine)?;
+        env_override("WDR_MAUS_LOCK_PATH", &mut self.paths.lock)?;
+        env_override("WDR_MAUS_WORK_PATH", &mut self.paths.work)?;
+        env_override("WDR_MAUS_NICE", &mut self.tools.nice)?;
+        env_override("WDR_MAUS_FFMPEG", &mut self.tools.ffmpeg)?;
+        env_override("WDR_MAUS_FFPROBE", &mut self.tools.ffprobe)?;
+        env_override("WDR_MAUS_MAGICK", &mut self.tools.magick)?;
+        env_override(
+            "WDR_MAUS_UPLOAD_LIMIT_MEGABYTES",
+            &mut self.telegram.upload_limit_megabytes,
+        )?;
+        env_override("WDR_MAUS_OUTPUT_TELEGRAM", &mut self.outputs.telegram)?;
+        if let Some(directory) = std::env::var_os("WDR_MAUS_ARCHIVE_DIRECTORY") {
+            let layout = self
+                .outputs
+                .archive
+                .as_ref()
+                .map(|archive| archive.layout)
+                .unwrap_or_default();
+            self.outputs.archive = Some(ArchiveConfig {
+                directory: directory.into(),
+                layout,
+            });
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.telegram.public_channel != 0,
+            "telegram.public_channel is not set"
+        );
+        anyhow::ensure!(
+            self.telegram.meta_channel != 0,
+            "telegram.meta_channel is not set"
+        );
+        anyhow::ensure!(
+            self.telegram.upload_limit_megabytes > 0,
+            "telegram.upload_limit_megabytes has to be positive"
+        );
+
+        for (name, url) in [
+            ("sources.aktuelle", &self.sources.aktuelle),
+            ("sources.sachgeschichten", &self.sources.sachgeschichten),
+            ("sources.zukunft", &self.sources.zukunft),
+        ] {
+            anyhow::ensure!(
+                matches!(url.scheme(), "http" | "https"),
+                "{name} has to be a http(s) url but is {url}"
+            );
+        }
+
+        self.validate_schedule()?;
+        anyhow::ensure!(
+            self.retry.max_attempts > 0,
+            "retry.max_attempts has to be positive"
+        );
+        anyhow::ensure!(
+            self.lock.stale_hours > 0,
+            "lock.stale_hours has to be positive"
+        );
+        anyhow::ensure!(
+            self.quarantine.threshold > 0,
+            "quarantine.threshold has to be positive"
+        );
+
+        for (name, path) in [
+            ("paths.daily", &self.paths.daily),
+            ("paths.database", &self.paths.database),
+            ("paths.downloaded", &self.paths.downloaded),
+            ("paths.quarantine", &self.paths.quarantine),
+            ("paths.lock", &self.paths.lock),
+            ("paths.work", &self.paths.work),
+            ("tools.nice", &self.tools.nice),
+            ("tools.ffmpeg", &self.tools.ffmpeg),
+            ("tools.ffprobe", &self.tools.ffprobe),
+            ("tools.magick", &self.tools.magick),
+        ] {
+            anyhow::ensure!(!path.as_os_str().is_empty(), "{name} is empty");
+        }
+
+        match self.quality {
+            Quality::Highest => {}
+            Quality::MaxHeight { height } => {
+                anyhow::ensure!(height > 0, "quality.height has to be positive");
+            }
+            Quality::MaxFilesize { megabytes } => {
+                anyhow::ensure!(megabytes > 0, "quality.megabytes has to be positive");
+            }
+        }
+
+        self.validate_encoding()?;
+        let pipeline = &self.pipeline;
+        for (name, value) in [
+            ("parallel.variants", self.parallel.variants),
+            ("pipeline.scrape_workers", pipeline.scrape_workers),
+            ("pipeline.download_workers", pipeline.download_workers),
+            ("pipeline.upload_workers", pipeline.upload_workers),
+            ("pipeline.queue_size", pipeline.queue_size),
+            ("pipeline.quota", pipeline.quota),
+        ] {
+            anyhow::ensure!(value > 0, "{name} has to be positive");
+        }
+
+        anyhow::ensure!(
+            self.outputs.telegram || self.outputs.archive.is_some(),
+            "no output is enabled, enable outputs.telegram and/or outputs.archive"
+        );
+        if let Some(archive) = &self.outputs.archive {
+            anyhow::ensure!(
+                !archive.directory.as_os_str().is_empty(),
+                "outputs.archive.directory is empty"
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_schedule(&self) -> anyhow::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for job in &self.schedule.jobs {
+            let name = &job.name;
+            anyhow::ensure!(!name.is_empty(), "schedule contains a job without name");
+            anyhow::ensure!(
+                names.insert(name),
+                "schedule contains the job {name} multiple times"
+            );
+            anyhow::ensure!(
+                job.from < 24,
+                "schedule {name} from has to be an hour (0-23) but is {}",
+                job.from
+            );
+            anyhow::ensure!(
+                job.from < job.until && job.until <= 24,
+                "schedule {name} until ({}) has to be after from ({}) and at most 24",
+                job.until,
+                job.from
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_encoding(&self) -> anyhow::Result<()> {
+        let encoding = &self.encoding;
+        for (name, profile) in &encoding.profiles {
+            match profile {
+                Profile::Copy => {}
+                Profile::H264 { crf, preset } | Profile::H265 { crf, preset } => {
+                    anyhow::ensure!(
+                        *crf <= 51,
+                        "encoding.profiles.{name}.crf has to be 0-51 but is {crf}"
+                    );
+                    anyhow::ensure!(
+                        !preset.is_empty(),
+                        "encoding.profiles.{name}.preset is empty"
+                    );
+                }
+                Profile::TargetSize { megabytes, preset } => {
+                    anyhow::ensure!(
+                        *megabytes > 0,
+                        "encoding.profiles.{name}.megabytes has to be positive"
+                    );
+                    anyhow::ensure!(
+                        !preset.is_empty(),
+                        "encoding.profiles.{name}.preset is empty"
+                    );
+                }
+                Profile::Auto {
+                    megabytes, encode, ..
+                } => {
+                    anyhow::ensure!(
+                        megabytes.is_none_or(|megabytes| megabytes > 0),
+                        "encoding.profiles.{name}.megabytes has to be positive"
+                    );
+                    anyhow::ensure!(
+                        matches!(
+                            encoding.profile(encode),
+                            Some(profile) if !matches!(profile, Profile::Auto { .. })
+                        ),
+                        "encoding.profiles.{name}.encode has to reference a profile which is not auto but is {encode:?}"
+                    );
+                }
+            }
+        }
+
+        let references = std::iter::once(("encoding.default".to_owned(), &encoding.default))
+            .chain(
+                encoding
+                    .topics
+                    .iter()
+                    .map(|(topic, name)| (format!("encoding.topics.{topic}"), name)),
+            )
+            .chain(
+                encoding
+                    .outputs
+                    .iter()
+                    .map(|(output, name)| (format!("encoding.outputs.{output}"), name)),
+            );
+        for (key, name) in references {
+            anyhow::ensure!(
+                encoding.profile(name).is_some(),
+                "{key} references the unknown profile {name:?}"
+            );
+        }
+        for output in encoding.outputs.keys() {
+            anyhow::ensure!(
+                matches!(output.as_str(), "telegram" | "archive"),
+                "encoding.outputs.{output} is not an output, use telegram or archive"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn env_override<T>(name: &str, target: &mut T) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Ok(value) = std::env::var(name) {
+        *target = value
+            .parse()
+            .with_context(|| format!("environment variable {name} is invalid: {value:?}"))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn defaults_are_valid() {
+    Config::default().validate().unwrap();
+    Config::parse("").unwrap().validate().unwrap();
+}
+
+#[test]
+fn partial_config_keeps_defaults() {
+    let config =
+        Config::parse("telegram:\n  public_channel: -42\nquality:\n  policy: highest\n").unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.telegram.public_channel, -42);
+    assert_eq!(
+        config.telegram.meta_channel,
+        TelegramConfig::default().meta_channel
+    );
+    assert_eq!(config.schedule.jobs.len(), 4);
+}
+
+#[test]
+fn unknown_field_is_rejected() {
+    assert!(Config::parse("telegram:\n  public_chanel: -42\n").is_err());
+}
+
+#[test]
+fn schedule_jobs() {
+    let config = Config::parse(
+        "schedule:\n  - name: preview\n    source: aktuell\n    weekdays: [sat]\n    from: 10\n    until: 12\n  - name: evening\n    source: sachgeschichten\n    from: 16\n",
+    )
+    .unwrap();
+    config.validate().unwrap();
+    let [preview, evening] = config.schedule.jobs.as_slice() else {
+        panic!("expected two jobs");
+    };
+    assert_eq!(preview.source, Source::Aktuell);
+    assert!(preview.is_due(Weekday::Sat, 10));
+    assert!(!preview.is_due(Weekday::Sat, 12));
+    assert!(!preview.is_due(Weekday::Sun, 10));
+    assert!(evening.is_due(Weekday::Mon, 23));
+    assert!(!evening.repeat);
+}
+
+#[test]
+fn invalid_schedule_is_rejected() {
+    let config =
+        Config::parse("schedule:\n  - name: a\n    source: aktuell\n    from: 14\n    until: 13\n")
+            .unwrap();
+    let error = config.validate().unwrap_err();
+    assert!(error.to_string().contains("until"));
+
+    let config =
+        Config::parse("schedule:\n  - name: a\n    source: aktuell\n    from: 24\n").unwrap();
+    assert!(config.validate().is_err());
+
+    let config = Config::parse(
+        "schedule:\n  - name: a\n    source: aktuell\n  - name: a\n    source: sachgeschichten\n",
+    )
+    .unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn outputs_are_validated() {
+    let config = Config::parse("outputs:\n  archive:\n    directory: /tmp/maus\n").unwrap();
+    config.validate().unwrap();
+    assert!(config.outputs.telegram);
+
+    let config =
+        Config::parse("outputs:\n  archive:\n    directory: /tmp/maus\n    layout: media_server\n")
+            .unwrap();
+    config.validate().unwrap();
+    assert_eq!(
+        config.outputs.archive.unwrap().layout,
+        ArchiveLayout::MediaServer
+    );
+
+    let config = Config::parse("outputs:\n  telegram: false\n").unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn quality_policy() {
+    assert_eq!(Config::default().quality, Quality::Highest);
+    let config = Config::parse("quality:\n  policy: max_height\n  height: 720\n").unwrap();
+    assert_eq!(config.quality, Quality::MaxHeight { height: 720 });
+    let config = Config::parse("quality:\n  policy: max_filesize\n  megabytes: 0\n").unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn encoding_profiles() {
+    let encoding = Config::default().encoding;
+    assert_eq!(
+        encoding.profile_name("telegram", Topic::Sachgeschichte),
+        "h265"
+    );
+    assert_eq!(
+        encoding.profile("h265"),
+        Some(Profile::H265 {
+            crf: 28,
+            preset: "medium".into()
+        })
+    );
+
+    let config = Config::parse(
+        "encoding:
+  topics:
+    Sachgeschichte: h264
+  outputs:
+    archive: small
+  profiles:
+    small:
+      codec: target_size
+      megabytes: 200
+",
+    )
+    .unwrap();
+    config.validate().unwrap();
+    let encoding = &config.encoding;
+    assert_eq!(
+        encoding.profile_name("telegram", Topic::Sachgeschichte),
+        "h264"
+    );
+    assert_eq!(
+        encoding.profile_name("telegram", Topic::AktuelleSendung),
+        "h265"
+    );
+    assert_eq!(
+        encoding.profile_name("archive", Topic::Sachgeschichte),
+        "small"
+    );
+    assert_eq!(
+        encoding.profile("small"),
+        Some(Profile::TargetSize {
+            megabytes: 200,
+            preset: "medium".into()
+        })
+    );
+
+    let config =
+        Config::parse("encoding:\n  profiles:\n    loop:\n      codec: auto\n      encode: auto\n")
+            .unwrap();
+    assert!(config.validate().is_err());
+    let config = Config::parse("encoding:\n  default: av1\n").unwrap();
+    assert!(config.validate().is_err());
+    let config = Config::parse("encoding:\n  outputs:\n    youtube: copy\n").unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn upload_limit() {
+    let config = Config::default();
+    assert_eq!(config.telegram.upload_limit_megabytes, 2000);
+    assert_eq!(config.telegram.oversize, Oversize::Split);
+    let config =
+        Config::parse("telegram:\n  upload_limit_megabytes: 50\n  oversize: reencode\n").unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.telegram.oversize, Oversize::Reencode);
+    let config = Config::parse("telegram:\n  upload_limit_megabytes: 0\n").unwrap();
+    assert!(config.validate().is_err());
+}
@@ -1,31 +1,45 @@
 use std::collections::HashMap;
 
-use chrono::{Datelike as _, Local, NaiveDate, Timelike as _, Weekday};
+use chrono::{Datelike as _, Local, NaiveDate, NaiveDateTime, TimeDelta, Timelike as _};
 use serde::{Deserialize, Serialize};
 
-const DAILY_PATH: &str = "daily.yaml";
-
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
-pub enum Job {
-    AktuelleSunday,
-    AktuelleCheckup,
-    SachgeschichteMorning,
-    SachgeschichteEvening,
-}
+use crate::config::{self, Job, Retry};
+use crate::persist;
 
 #[derive(Serialize, Deserialize)]
 pub struct Daily {
     day: NaiveDate,
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    jobs: HashMap<Job, bool>,
+    jobs: HashMap<String, JobState>,
+}
+
+/// Finished jobs are stored as `true` like before the retries were tracked
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum JobState {
+    Done(bool),
+    Failed {
+        attempts: u32,
+        last_error: String,
+        /// Local time from which on the job is retried
+        next_run: NaiveDateTime,
+    },
+}
+
+/// What happened to a job which failed
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    RetryAt(NaiveDateTime),
+    /// No more attempts today
+    GaveUp {
+        attempts: u32,
+    },
 }
 
 impl Daily {
     pub fn new() -> Self {
         let today = Local::now().date_naive();
-        std::fs::read_to_string(DAILY_PATH)
-            .map(|content| serde_yaml::from_str::<Self>(&content).expect("daily.yaml format error"))
-            .ok()
+        persist::read::<Self>(&config::get().paths.daily)
             .filter(|file| file.day == today)
             .unwrap_or_else(|| Self {
                 day: today,
@@ -34,20 +48,64 @@ impl Daily {
     }
 
     fn write(&self) {
-        let content = serde_yaml::to_string(self).unwrap();
-        std::fs::write(DAILY_PATH, content).expect("failed to write daily.yaml");
+        persist::write(&config::get().paths.daily, self);
+    }
+
+    pub fn mark_successful(&mut self, job: &Job) {
+        self.jobs.insert(job.name.clone(), JobState::Done(true));
+        self.write();
     }
 
-    pub fn mark_successful(&mut self, job: Job) {
-        self.jobs.insert(job, true);
+    /// Remember the failed attempt and when to try again
+    pub fn mark_failed(&mut self, job: &Job, error: &str) -> Failure {
+        let failure =
+            self.record_failure(job, error, Local::now().naive_local(), &config::get().retry);
         self.write();
+        failure
+    }
+
+    fn record_failure(
+        &mut self,
+        job: &Job,
+        error: &str,
+        now: NaiveDateTime,
+        retry: &Retry,
+    ) -> Failure {
+        let attempts = match self.jobs.get(&job.name) {
+            Some(JobState::Failed { attempts, .. }) => attempts + 1,
+            _ => 1,
+        };
+        let backoff = retry
+            .backoff_minutes
+            .saturating_mul(1 << (attempts - 1).min(16));
+        let next_run = now + TimeDelta::minutes(i64::from(backoff));
+        self.jobs.insert(
+            job.name.clone(),
+            JobState::Failed {
+                attempts,
+                last_error: error.to_owned(),
+                next_run,
+            },
+        );
+        if attempts >= retry.max_attempts {
+            Failure::GaveUp { attempts }
+        } else {
+            Failure::RetryAt(next_run)
+        }
     }
 
-    fn is_done(&self, job: Job) -> bool {
-        self.jobs.get(&job).copied().unwrap_or(false)
+    /// Whether the job may run (again) at the given time
+    fn is_allowed(&self, job: &Job, now: NaiveDateTime, retry: &Retry) -> bool {
+        match self.jobs.get(&job.name) {
+            None | Some(JobState::Done(false)) => true,
+            Some(JobState::Done(true)) => job.repeat,
+            Some(JobState::Failed {
+                attempts, next_run, ..
+            }) => *attempts < retry.max_attempts && *next_run <= now,
+        }
     }
 
-    pub fn get_next(&self) -> Option<Job> {
+    pub fn get_next(&self) -> Option<&'static Job> {
         let now = Local::now();
         println!(
             "check do_next… {:>2}:{:>02} {}",
@@ -55,17 +113,103 @@ impl Daily {
             now.minute(),
             now.weekday()
         );
+        let config = config::get();
+        self.next_due(&config.schedule.jobs, now.naive_local(), &config.retry)
+    }
 
-        if now.weekday() == Weekday::Sun && now.hour() >= 8 && now.hour() < 13 {
-            Some(Job::AktuelleSunday)
-        } else if !self.is_done(Job::AktuelleCheckup) && now.hour() >= 19 {
-            Some(Job::AktuelleCheckup)
-        } else if !self.is_done(Job::SachgeschichteMorning) && now.hour() >= 5 {
-            Some(Job::SachgeschichteMorning)
-        } else if !self.is_done(Job::SachgeschichteEvening) && now.hour() >= 16 {
-            Some(Job::SachgeschichteEvening)
-        } else {
-            None
-        }
+    fn next_due<'j>(&self, jobs: &'j [Job], now: NaiveDateTime, retry: &Retry) -> Option<&'j Job> {
+        jobs.iter()
+            .find(|job| job.is_due(now.weekday(), now.hour()) && self.is_allowed(job, now, retry))
     }
 }
+
+#[cfg(test)]
+const fn at(day: u32, hour: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2026, 10, day)
+        .unwrap()
+        .and_hms_opt(hour, 0, 0)
+        .unwrap()
+}
+
+#[test]
+fn default_schedule() {
+    const MONDAY: u32 = 19;
+    const SUNDAY: u32 = 18;
+
+    let jobs = config::Schedule::default().jobs;
+    let retry = Retry::default();
+    let mut daily = Daily {
+        day: NaiveDate::default(),
+        jobs: HashMap::new(),
+    };
+    let next = |daily: &Daily, day, hour| {
+        daily
+            .next_due(&jobs, at(day, hour), &retry)
+            .map(|job| job.name.as_str())
+    };
+
+    assert_eq!(next(&daily, MONDAY, 4), None);
+    assert_eq!(next(&daily, MONDAY, 5), Some("SachgeschichteMorning"));
+    assert_eq!(next(&daily, SUNDAY, 9), Some("AktuelleSunday"));
+    assert_eq!(next(&daily, MONDAY, 20), Some("AktuelleCheckup"));
+
+    daily
+        .jobs
+        .insert("AktuelleSunday".into(), JobState::Done(true));
+    daily
+        .jobs
+        .insert("AktuelleCheckup".into(), JobState::Done(true));
+    assert_eq!(next(&daily, SUNDAY, 9), Some("AktuelleSunday"));
+    assert_eq!(next(&daily, MONDAY, 20), Some("SachgeschichteMorning"));
+    daily
+        .jobs
+        .insert("SachgeschichteMorning".into(), JobState::Done(true));
+    assert_eq!(next(&daily, MONDAY, 20), Some("SachgeschichteEvening"));
+    assert_eq!(next(&daily, MONDAY, 15), None);
+}
+
+#[test]
+fn failed_jobs_back_off_and_give_up() {
+    let jobs = config::Schedule::default().jobs;
+    let job = &jobs[1]; // AktuelleCheckup
+    let retry = Retry {
+        max_attempts: 3,
+        backoff_minutes: 10,
+    };
+    let mut daily = Daily {
+        day: NaiveDate::default(),
+        jobs: HashMap::new(),
+    };
+    let now = at(19, 20);
+    let is_next = |daily: &Daily, now| {
+        daily
+            .next_due(&jobs, now, &retry)
+            .is_some_and(|next| next == job)
+    };
+
+    let failure = daily.record_failure(job, "first", now, &retry);
+    assert_eq!(failure, Failure::RetryAt(now + TimeDelta::minutes(10)));
+    assert!(!is_next(&daily, now + TimeDelta::minutes(5)));
+    assert!(is_next(&daily, now + TimeDelta::minutes(10)));
+
+    let failure = daily.record_failure(job, "second", now, &retry);
+    assert_eq!(failure, Failure::RetryAt(now + TimeDelta::minutes(20)));
+    assert!(!is_next(&daily, now + TimeDelta::minutes(10)));
+
+    let failure = daily.record_failure(job, "third", now, &retry);
+    assert_eq!(failure, Failure::GaveUp { attempts: 3 });
+    assert!(!is_next(&daily, now + TimeDelta::hours(3)));
+}
+
+#[test]
+fn reads_bool_entries() {
+    let daily = serde_yaml::from_str::<Daily>(
+        "day: 2026-10-19\njobs:\n  AktuelleCheckup: true\n  SachgeschichteMorning:\n    attempts: 2\n    last_error: offline\n    next_run: 2026-10-19T06:00:00\n",
+    )
+    .unwrap();
+    assert_eq!(daily.jobs["AktuelleCheckup"], JobState::Done(true));
+    assert!(matches!(
+        daily.jobs["SachgeschichteMorning"],
+        JobState::Failed { attempts: 2, .. }
+    ));
+}
@@ -1,34 +1,216 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use chrono::{DateTime, Local};
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::persist;
+use crate::publisher::Receipt;
 use crate::wdr_media::WdrMedia;
 
-const DOWNLOADED_PATH: &str = "downloaded.yaml";
+/// Details of a published video to audit and later edit or repost it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Publication {
+    /// Begin of the download
+    pub started: DateTime<Local>,
+    pub published: DateTime<Local>,
+    pub score: usize,
+    #[serde(flatten)]
+    pub receipt: Receipt,
+    pub files: Vec<PublishedFile>,
+}
 
+/// An encoded file handed to the publishers.
+/// Size and hash describe this file, not the parts Telegram uploads when it has to split or shrink it to fit the upload limit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublishedFile {
+    /// Encoding profile
+    pub profile: String,
+    /// Normal, DGS or AD
+    pub variant: String,
+    /// In bytes
+    pub size: u64,
+    pub sha256: String,
+    /// In seconds
+    pub duration: Option<f64>,
+}
+
+/// History of the downloaded videos in a `SQLite` database
 pub struct Downloaded {
-    list: Vec<WdrMedia>,
+    connection: Mutex<Connection>,
 }
 
 impl Downloaded {
-    #[must_use]
-    pub fn new() -> Self {
-        let list = std::fs::read_to_string(DOWNLOADED_PATH)
-            .map(|content| serde_yaml::from_str(&content).expect("downloaded.yaml format error"))
-            .unwrap_or_default();
-        Self { list }
+    pub fn new() -> anyhow::Result<Self> {
+        let path = &config::get().paths.database;
+        let connection =
+            Connection::open(path).with_context(|| format!("open {}", path.display()))?;
+        connection.busy_timeout(Duration::from_secs(10))?;
+        Ok(Self::init(connection)?)
+    }
+
+    /// Import the former `downloaded.yaml`. Only call while holding the instance lock.
+    pub fn migrate() -> anyhow::Result<()> {
+        Self::new()?.migrate_yaml(&config::get().paths.downloaded)
+    }
+
+    fn init(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS downloaded (
+                id TEXT NOT NULL,
+                score INTEGER NOT NULL,
+                media TEXT NOT NULL UNIQUE
+            );
+            CREATE INDEX IF NOT EXISTS downloaded_id ON downloaded (id, score);",
+        )?;
+        let version =
+            connection.pragma_query_value(None, "user_version", |row| row.get::<_, u32>(0))?;
+        if version < 1 {
+            connection.execute_batch(
+                "ALTER TABLE downloaded ADD COLUMN publication TEXT;
+                PRAGMA user_version = 1;",
+            )?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Import the former `downloaded.yaml` once. The file is renamed to `*.migrated` afterwards.
+    /// A broken file is moved aside and reported instead of migrated.
+    fn migrate_yaml(&self, path: &Path) -> anyhow::Result<()> {
+        let Some(list) = persist::read::<Vec<WdrMedia>>(path) else {
+            return Ok(());
+        };
+        self.insert(&list)?;
+        let mut migrated = path.as_os_str().to_owned();
+        migrated.push(".migrated");
+        std::fs::rename(path, &migrated).context("rename migrated yaml")?;
+        println!("migrated {} entries from {}", list.len(), path.display());
+        Ok(())
+    }
+
+    fn insert(&self, list: &[WdrMedia]) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for media in list {
+            transaction.execute(
+                "INSERT OR IGNORE INTO downloaded (id, score, media) VALUES (?1, ?2, ?3)",
+                params![
+                    media.tracker_data.id,
+                    i64::try_from(media.media_resource.score())?,
+                    serde_json::to_string(media)?,
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        drop(connection);
+        Ok(())
     }
 
-    pub fn was_downloaded(&self, media: &WdrMedia) -> bool {
+    /// Entries of the given tracker id with at least the given score
+    fn query(&self, id: &str, min_score: usize) -> anyhow::Result<Vec<WdrMedia>> {
+        self.select(
+            "SELECT media FROM downloaded WHERE id = ?1 AND score >= ?2",
+            params![id, i64::try_from(min_score)?],
+        )
+    }
+
+    fn select<P: rusqlite::Params>(&self, sql: &str, params: P) -> anyhow::Result<Vec<WdrMedia>> {
+        let connection = self.connection.lock().unwrap();
+        let rows = connection
+            .prepare_cached(sql)?
+            .query_map(params, |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(connection);
+        rows.iter()
+            .map(|json| Ok(serde_json::from_str(json)?))
+            .collect()
+    }
+
+    pub fn was_downloaded(&self, media: &WdrMedia) -> anyhow::Result<bool> {
         let new_score = media.media_resource.score();
-        self.list
+        Ok(self
+            .query(&media.tracker_data.id, new_score)
+            .context("failed to query the downloaded database")?
             .iter()
-            .filter(|wdrmedia| wdrmedia.tracker_data == media.tracker_data)
-            .any(|wdrmedia| wdrmedia.media_resource.score() >= new_score)
+            .any(|wdrmedia| wdrmedia.tracker_data == media.tracker_data))
     }
 
-    pub fn mark_downloaded(media: WdrMedia) {
-        let mut list = Self::new().list;
-        list.push(media);
+    pub fn list(&self) -> anyhow::Result<Vec<WdrMedia>> {
+        let mut list = self
+            .select("SELECT media FROM downloaded", [])
+            .context("failed to query the downloaded database")?;
         list.sort();
-        let content = serde_yaml::to_string(&list).unwrap();
-        std::fs::write(DOWNLOADED_PATH, content).expect("failed to write downloaded.yaml");
+        Ok(list)
+    }
+
+    /// Publications of the given tracker id, oldest first
+    pub fn publications(&self, id: &str) -> anyhow::Result<Vec<(WdrMedia, Publication)>> {
+        Ok(self
+            .history(id)?
+            .into_iter()
+            .filter_map(|(media, publication)| Some((media, publication?)))
+            .collect())
+    }
+
+    /// Entries of the given tracker id, oldest first.
+    /// Without publication when it was not recorded, like for entries migrated from `downloaded.yaml`.
+    pub fn history(&self, id: &str) -> anyhow::Result<Vec<(WdrMedia, Option<Publication>)>> {
+        let connection = self.connection.lock().unwrap();
+        let rows = connection
+            .prepare_cached(
+                "SELECT media, publication FROM downloaded WHERE id = ?1 ORDER BY rowid",
+            )?
+            .query_map([id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(connection);
+        rows.iter()
+            .map(|(media, publication)| {
+                Ok((
+                    serde_json::from_str(media)?,
+                    publication
+                        .as_deref()
+                        .map(serde_json::from_str)
+                        .transpose()?,
+                ))
+            })
+            .collect()
+    }
+
+    fn record(&self, media: &WdrMedia, publication: &Publication) -> anyhow::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO downloaded (id, score, media, publication) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (media) DO UPDATE SET publication = excluded.publication",
+            params![
+                media.tracker_data.id,
+                i64::try_from(media.media_resource.score())?,
+                serde_json::to_string(media)?,
+                serde_json::to_string(publication)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn mark_downloaded(media: &WdrMedia, publication: &Publication) -> anyhow::Result<()> {
+        Self::new()?
+            .record(media, publication)
+            .context("failed to write the downloaded database")
+    }
+
+    /// Remove every entry of the given tracker id. Returns the amount of removed entries.
+    pub fn forget(id: &str) -> anyhow::Result<usize> {
+        Ok(Self::new()?
+            .connection
+            .into_inner()
+            .unwrap()
+            .execute("DELETE FROM downloaded WHERE id = ?1", [id])?)
     }
 }
 
@@ -41,6 +223,16 @@ mod tests {
     use super::*;
     use crate::wdr_media::{Captions, MediaFormat, MediaResource, MediaResources, TrackerData};
 
+    fn with(list: &[&WdrMedia]) -> Downloaded {
+        let downloaded = Downloaded::init(Connection::open_in_memory().unwrap()).unwrap();
+        let list = list
+            .iter()
+            .map(|media| (*media).clone())
+            .collect::<Vec<_>>();
+        downloaded.insert(&list).unwrap();
+        downloaded
+    }
+
     static A0: LazyLock<WdrMedia> = LazyLock::new(|| WdrMedia {
         tracker_data: TrackerData {
             id: "a".into(),
@@ -61,7 +253,7 @@ mod tests {
                 sl_video: None,
                 ad_video: None,
             },
-            captions_hash: Captions { srt: None },
+            captions_hash: Captions::default(),
         },
     });
     static A1: LazyLock<WdrMedia> = LazyLock::new(|| WdrMedia {
@@ -84,7 +276,7 @@ mod tests {
                 sl_video: None,
                 ad_video: None,
             },
-            captions_hash: Captions { srt: None },
+            captions_hash: Captions::default(),
         },
     });
     static A2: LazyLock<WdrMedia> = LazyLock::new(|| WdrMedia {
@@ -109,6 +301,7 @@ mod tests {
             },
             captions_hash: Captions {
                 srt: Url::parse("https://edjopato.de").ok(),
+                ..Captions::default()
             },
         },
     });
@@ -133,10 +326,75 @@ mod tests {
                 sl_video: None,
                 ad_video: None,
             },
-            captions_hash: Captions { srt: None },
+            captions_hash: Captions::default(),
         },
     });
 
+    #[test]
+    fn duplicates_are_ignored() {
+        let downloaded = with(&[&*A0, &*A0, &*B]);
+        assert_eq!(downloaded.list().unwrap().len(), 2);
+        downloaded.insert(std::slice::from_ref(&A1)).unwrap();
+        assert_eq!(
+            downloaded.list().unwrap(),
+            [A0.clone(), A1.clone(), B.clone()]
+        );
+    }
+
+    #[test]
+    fn migrate_yaml_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("downloaded.yaml");
+        std::fs::write(&path, serde_yaml::to_string(&[&*A0, &*B]).unwrap()).unwrap();
+
+        let downloaded = with(&[]);
+        downloaded.migrate_yaml(&path).unwrap();
+        assert_eq!(downloaded.list().unwrap(), [A0.clone(), B.clone()]);
+        assert!(!path.exists());
+        assert!(dir.path().join("downloaded.yaml.migrated").exists());
+
+        downloaded.migrate_yaml(&path).unwrap();
+        assert_eq!(downloaded.list().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn publication_is_kept() {
+        let downloaded = with(&[&*A0]);
+        assert!(downloaded.publications("a").unwrap().is_empty());
+
+        let publication = Publication {
+            started: Local::now(),
+            published: Local::now(),
+            score: 1,
+            receipt: Receipt {
+                public_messages: vec![42, 43],
+                meta_message: Some(7),
+                failures: Vec::new(),
+            },
+            files: vec![PublishedFile {
+                profile: "auto".into(),
+                variant: "normal".into(),
+                size: 1234,
+                sha256: "abc".into(),
+                duration: Some(1783.68),
+            }],
+        };
+        downloaded.record(&A1, &publication).unwrap();
+        downloaded.record(&A1, &publication).unwrap();
+        assert_eq!(downloaded.list().unwrap(), [A0.clone(), A1.clone()]);
+        assert_eq!(
+            downloaded.publications("a").unwrap(),
+            [(A1.clone(), publication)]
+        );
+        assert!(downloaded.was_downloaded(&A1).unwrap());
+    }
+
+    #[test]
+    fn history_includes_unrecorded_publications() {
+        let downloaded = with(&[&*A0, &*B]);
+        assert_eq!(downloaded.history("a").unwrap(), [(A0.clone(), None)]);
+    }
+
     #[test]
     fn score() {
         assert_eq!(0, A0.media_resource.score());
@@ -147,91 +405,75 @@ mod tests {
 
     #[test]
     fn empty_wasnt_downloaded() {
-        let downloaded = Downloaded { list: vec![] };
-        assert!(!downloaded.was_downloaded(&A0));
-        assert!(!downloaded.was_downloaded(&A1));
-        assert!(!downloaded.was_downloaded(&A2));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[]);
+        assert!(!downloaded.was_downloaded(&A0).unwrap());
+        assert!(!downloaded.was_downloaded(&A1).unwrap());
+        assert!(!downloaded.was_downloaded(&A2).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
     }
 
     #[test]
     fn a_differs_b() {
-        let downloaded = Downloaded {
-            list: vec![A0.clone()],
-        };
-        assert!(downloaded.was_downloaded(&A0));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*A0]);
+        assert!(downloaded.was_downloaded(&A0).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
 
-        let downloaded = Downloaded {
-            list: vec![B.clone()],
-        };
-        assert!(!downloaded.was_downloaded(&A0));
-        assert!(downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*B]);
+        assert!(!downloaded.was_downloaded(&A0).unwrap());
+        assert!(downloaded.was_downloaded(&B).unwrap());
     }
 
     #[test]
     fn upgrade_a0() {
-        let downloaded = Downloaded {
-            list: vec![A0.clone()],
-        };
-        assert!(downloaded.was_downloaded(&A0));
-        assert!(!downloaded.was_downloaded(&A1));
-        assert!(!downloaded.was_downloaded(&A2));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*A0]);
+        assert!(downloaded.was_downloaded(&A0).unwrap());
+        assert!(!downloaded.was_downloaded(&A1).unwrap());
+        assert!(!downloaded.was_downloaded(&A2).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
     }
 
     #[test]
     fn upgrade_a1() {
-        let downloaded = Downloaded {
-            list: vec![A1.clone()],
-        };
-        assert!(downloaded.was_downloaded(&A0));
-        assert!(downloaded.was_downloaded(&A1));
-        assert!(!downloaded.was_downloaded(&A2));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*A1]);
+        assert!(downloaded.was_downloaded(&A0).unwrap());
+        assert!(downloaded.was_downloaded(&A1).unwrap());
+        assert!(!downloaded.was_downloaded(&A2).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
     }
 
     #[test]
     fn upgrade_a2() {
-        let downloaded = Downloaded {
-            list: vec![A2.clone()],
-        };
-        assert!(downloaded.was_downloaded(&A0));
-        assert!(downloaded.was_downloaded(&A1));
-        assert!(downloaded.was_downloaded(&A2));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*A2]);
+        assert!(downloaded.was_downloaded(&A0).unwrap());
+        assert!(downloaded.was_downloaded(&A1).unwrap());
+        assert!(downloaded.was_downloaded(&A2).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
     }
 
     #[test]
     fn upgrade_a0_and_a1() {
-        let downloaded = Downloaded {
-            list: vec![A0.clone(), A1.clone()],
-        };
-        assert!(downloaded.was_downloaded(&A0));
-        assert!(downloaded.was_downloaded(&A1));
-        assert!(!downloaded.was_downloaded(&A2));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*A0, &*A1]);
+        assert!(downloaded.was_downloaded(&A0).unwrap());
+        assert!(downloaded.was_downloaded(&A1).unwrap());
+        assert!(!downloaded.was_downloaded(&A2).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
     }
 
     #[test]
     fn upgrade_a0_and_a2() {
-        let downloaded = Downloaded {
-            list: vec![A0.clone(), A2.clone()],
-        };
-        assert!(downloaded.was_downloaded(&A0));
-        assert!(downloaded.was_downloaded(&A1));
-        assert!(downloaded.was_downloaded(&A2));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*A0, &*A2]);
+        assert!(downloaded.was_downloaded(&A0).unwrap());
+        assert!(downloaded.was_downloaded(&A1).unwrap());
+        assert!(downloaded.was_downloaded(&A2).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
     }
 
     #[test]
     fn upgrade_a1_and_a2() {
-        let downloaded = Downloaded {
-            list: vec![A1.clone(), A2.clone()],
-        };
-        assert!(downloaded.was_downloaded(&A0));
-        assert!(downloaded.was_downloaded(&A1));
-        assert!(downloaded.was_downloaded(&A2));
-        assert!(!downloaded.was_downloaded(&B));
+        let downloaded = with(&[&*A1, &*A2]);
+        assert!(downloaded.was_downloaded(&A0).unwrap());
+        assert!(downloaded.was_downloaded(&A1).unwrap());
+        assert!(downloaded.was_downloaded(&A2).unwrap());
+        assert!(!downloaded.was_downloaded(&B).unwrap());
     }
 }
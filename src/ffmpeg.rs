@@ -1,21 +1,28 @@
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::io::{BufRead as _, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
 
-use anyhow::Context as _;
-use lazy_regex::regex;
-use tempfile::NamedTempFile;
+use chrono::NaiveDate;
+use tempfile::{NamedTempFile, TempDir};
 use url::Url;
 
-use crate::temporary::get_tempfile;
+use crate::config::{self, Profile};
+use crate::ffprobe::VideoStats;
+use crate::temporary::{get_tempdir, get_tempfile};
+use crate::{hls, segments};
 
 fn ffmpeg() -> Command {
-    let mut command = Command::new("nice");
+    let tools = &config::get().tools;
+    let mut command = Command::new(&tools.nice);
     command
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .arg("-n17")
-        .arg("ffmpeg")
+        .arg(&tools.ffmpeg)
         .arg("-y")
         .args(["-v", "error"]);
     command
@@ -23,11 +30,15 @@ fn ffmpeg() -> Command {
 
 fn run_command(mut command: Command) -> anyhow::Result<()> {
     let status = command.status().expect("failed to execute ffmpeg");
+    check_status(&command, status)
+}
+
+fn check_status(command: &Command, status: ExitStatus) -> anyhow::Result<()> {
     if !status.success() {
         let command_line = command
             .get_args()
             .skip(1)
-            .map(std::ffi::OsStr::to_string_lossy)
+            .map(OsStr::to_string_lossy)
             .collect::<Vec<_>>()
             .join(" ");
         anyhow::bail!("ffmpeg exited unsuccessfully. Commandline: {command_line}");
@@ -35,29 +46,433 @@ fn run_command(mut command: Command) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn download(video: &Url, caption_srt: Option<&Url>) -> anyhow::Result<NamedTempFile> {
-    let file = get_tempfile(".mp4")?;
-    let mut command = ffmpeg();
+/// Tags of the resulting MP4
+pub struct Metadata<'a> {
+    pub title: &'a str,
+    pub date: Option<NaiveDate>,
+    pub comment: String,
+    pub genre: String,
+    /// Attached as cover art
+    pub cover: Option<&'a Path>,
+}
+
+impl Metadata<'_> {
+    fn args(&self, command: &mut Command) {
+        command
+            .arg("-metadata")
+            .arg(format!("title={}", self.title))
+            .arg("-metadata")
+            .arg(format!("comment={}", self.comment))
+            .arg("-metadata")
+            .arg(format!("genre={}", self.genre));
+        if let Some(date) = self.date {
+            command.arg("-metadata").arg(format!("date={date}"));
+        }
+        command.args(["-metadata:s:s", "language=deu"]);
+    }
+}
+
+/// Download the video once and encode it with each of the profiles.
+/// The resulting files are in the same order as the profiles.
+///
+/// `report` gets a human readable status of the running ffmpeg every now and then.
+pub fn download(
+    video: &Url,
+    subtitle: Option<&Path>,
+    metadata: &Metadata,
+    profiles: &[Profile],
+    report: &dyn Fn(&str),
+) -> anyhow::Result<Vec<NamedTempFile>> {
+    let stream = segments::fetch(video)?;
+    let path: OsString = stream.as_ref().map_or_else(
+        || video.as_str().into(),
+        |stream| stream.playlist.clone().into(),
+    );
+    let input = Input {
+        stats: VideoStats::load(&path),
+        path,
+        subtitle,
+        report,
+    };
+
+    let mut files = Vec::with_capacity(profiles.len());
+    for profile in profiles {
+        files.push(encode(&input, metadata, profile)?);
+    }
+
+    if let Some(stream) = stream {
+        stream.remove();
+    }
+    Ok(files)
+}
+
+struct Input<'a> {
+    path: OsString,
+    stats: anyhow::Result<VideoStats>,
+    subtitle: Option<&'a Path>,
+    report: &'a dyn Fn(&str),
+}
+
+impl Input<'_> {
+    fn stats(&self) -> anyhow::Result<&VideoStats> {
+        self.stats
+            .as_ref()
+            .map_err(|error| anyhow::anyhow!("ffprobe of the input failed: {error:#}"))
+    }
+
+    /// Duration of what is encoded in seconds
+    fn duration(&self) -> Option<f64> {
+        let duration = self.stats.as_ref().ok()?.duration;
+        // Only short sample for faster finish
+        #[cfg(debug_assertions)]
+        let duration = duration.min(5.0);
+        Some(duration)
+    }
+
+    fn command(&self) -> Command {
+        let mut command = ffmpeg();
+        command.args(["-progress", "pipe:1", "-nostats"]);
+
+        // Only short sample for faster finish
+        #[cfg(debug_assertions)]
+        command.args(["-t", "5"]);
+
+        command.args(hls::input_args(Path::new(&self.path)));
+        command.arg("-i").arg(&self.path);
+        if let Some(subtitle) = self.subtitle {
+            command.arg("-i").arg(subtitle.as_os_str());
+        }
+        command
+    }
+
+    /// Run a [`command`](Self::command) and report its progress at most every [`PROGRESS_INTERVAL`]
+    fn run(&self, mut command: Command, step: &str) -> anyhow::Result<()> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to execute ffmpeg");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let duration = self.duration();
+        let mut progress = Progress::default();
+        let mut last_report = Instant::now();
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if progress.update(&line) && last_report.elapsed() >= PROGRESS_INTERVAL {
+                last_report = Instant::now();
+                (self.report)(&format!("{step} {}", progress.status(duration)));
+            }
+        }
+        let status = child.wait()?;
+        check_status(&command, status)
+    }
+}
 
-    // Only short sample for faster finish
-    #[cfg(debug_assertions)]
-    command.args(["-t", "5"]);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// State of the `-progress` output of ffmpeg
+#[derive(Debug, Default)]
+struct Progress {
+    /// Position in the output in seconds
+    out_time: f64,
+    /// Compared to realtime
+    speed: Option<f64>,
+}
+
+impl Progress {
+    /// Returns true when a block of key value pairs is complete
+    fn update(&mut self, line: &str) -> bool {
+        let Some((key, value)) = line.split_once('=') else {
+            return false;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "out_time_us" => {
+                if let Ok(micros) = value.parse::<f64>() {
+                    self.out_time = (micros / 1_000_000.0).max(0.0);
+                }
+            }
+            "speed" => {
+                self.speed = value
+                    .trim_end_matches('x')
+                    .parse()
+                    .ok()
+                    .filter(|speed| *speed > 0.0);
+            }
+            "progress" => return true,
+            _ => {}
+        }
+        false
+    }
 
-    command.args(["-i", video.as_ref()]);
-    if let Some(caption) = caption_srt {
-        command.args(["-i", caption.as_ref()]);
+    fn status(&self, duration: Option<f64>) -> String {
+        let duration = duration.filter(|duration| *duration > 0.0);
+        let mut status = duration.map_or_else(
+            || format_clock(self.out_time),
+            |duration| {
+                let percent = (self.out_time / duration * 100.0).min(100.0);
+                format!(
+                    "{percent:.0}% ({} / {})",
+                    format_clock(self.out_time),
+                    format_clock(duration)
+                )
+            },
+        );
+        if let Some(speed) = self.speed {
+            write!(status, " at {speed:.1}x").unwrap();
+            if let Some(duration) = duration {
+                let eta = (duration - self.out_time).max(0.0) / speed;
+                write!(status, ", ETA {}", format_clock(eta)).unwrap();
+            }
+        }
+        status
     }
+}
+
+/// `m:ss` or `h:mm:ss`
+fn format_clock(seconds: f64) -> String {
+    #[expect(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let seconds = seconds.round() as u64;
+    let hours = seconds / 3600;
+    let minutes = (seconds / 60) % 60;
+    let seconds = seconds % 60;
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+fn encode(input: &Input, metadata: &Metadata, profile: &Profile) -> anyhow::Result<NamedTempFile> {
+    let file = get_tempfile(".mp4")?;
+    let profile = &resolve_auto(input, profile);
 
+    let bitrate = if let Profile::TargetSize { megabytes, .. } = profile {
+        let stats = input.stats()?;
+        Some(target_bitrate(*megabytes, stats.duration_seconds()))
+    } else {
+        None
+    };
+    let video_args = video_args(profile, bitrate);
+
+    let passlog = if bitrate.is_some() {
+        let directory = get_tempdir()?;
+        let passlog = directory.path().join("passlog");
+        let mut command = input.command();
+        command
+            .args(["-map", "0:v:0"])
+            .args(&video_args)
+            .args(["-pass", "1", "-passlogfile"])
+            .arg(passlog.as_os_str())
+            .args(["-an", "-f", "null"])
+            .arg("/dev/null");
+        input.run(command, "pass 1/2")?;
+        Some((directory, passlog))
+    } else {
+        None
+    };
+
+    let mut command = input.command();
     command
         .args(["-c", "copy"])
         .args(["-c:s", "mov_text"])
-        .args(["-c:v", "libx265"])
-        .arg(file.path().as_os_str());
+        .args(&video_args);
+    if let Some((_, passlog)) = &passlog {
+        command
+            .args(["-pass", "2", "-passlogfile"])
+            .arg(passlog.as_os_str());
+    }
+    metadata.args(&mut command);
+    command.arg(file.path().as_os_str());
+    let step = if passlog.is_some() {
+        "pass 2/2"
+    } else {
+        "encoding"
+    };
+    input.run(command, step)?;
 
-    run_command(command)?;
+    if let Some(cover) = metadata.cover {
+        return attach_cover(file.path(), cover);
+    }
     Ok(file)
 }
 
+/// Decide what an [`Profile::Auto`] does with this input
+fn resolve_auto(input: &Input, profile: &Profile) -> Profile {
+    let Profile::Auto {
+        codecs,
+        megabytes,
+        encode,
+    } = profile
+    else {
+        return profile.clone();
+    };
+    let encode_profile = || {
+        config::get()
+            .encoding
+            .profile(encode)
+            .expect("profiles are validated with the config")
+    };
+    let stats = match input.stats() {
+        Ok(stats) => stats,
+        Err(error) => {
+            println!("auto profile: encode with {encode} as the source is unknown: {error:#}");
+            return encode_profile();
+        }
+    };
+    let video_codec = stats.video().and_then(|video| video.codec.as_deref());
+    if source_fits(stats, codecs, *megabytes) {
+        println!(
+            "auto profile: keep {} {} source",
+            video_codec.unwrap_or_default(),
+            stats
+                .audio()
+                .and_then(|audio| audio.codec.as_deref())
+                .unwrap_or("without audio"),
+        );
+        Profile::Copy
+    } else {
+        println!(
+            "auto profile: encode {} source with {encode}",
+            video_codec.unwrap_or("unknown")
+        );
+        encode_profile()
+    }
+}
+
+/// The source can be remuxed without encoding
+fn source_fits(stats: &VideoStats, codecs: &[String], megabytes: Option<u64>) -> bool {
+    let codec_fits = stats
+        .video()
+        .and_then(|video| video.codec.as_ref())
+        .is_some_and(|codec| codecs.contains(codec));
+    let size_fits = megabytes.is_none_or(|megabytes| {
+        stats
+            .estimated_filesize()
+            .is_some_and(|filesize| filesize <= megabytes * 1024 * 1024)
+    });
+    codec_fits && size_fits
+}
+
+/// Arguments for the video stream. Everything else is copied.
+fn video_args(profile: &Profile, bitrate: Option<u64>) -> Vec<String> {
+    match profile {
+        // Auto is resolved before
+        Profile::Copy | Profile::Auto { .. } => Vec::new(),
+        Profile::H264 { crf, preset } => vec![
+            "-c:v".into(),
+            "libx264".into(),
+            "-crf".into(),
+            crf.to_string(),
+            "-preset".into(),
+            preset.clone(),
+        ],
+        Profile::H265 { crf, preset } => vec![
+            "-c:v".into(),
+            "libx265".into(),
+            "-crf".into(),
+            crf.to_string(),
+            "-preset".into(),
+            preset.clone(),
+        ],
+        Profile::TargetSize { preset, .. } => vec![
+            "-c:v".into(),
+            "libx264".into(),
+            "-b:v".into(),
+            bitrate.unwrap_or_default().to_string(),
+            "-preset".into(),
+            preset.clone(),
+        ],
+    }
+}
+
+/// Video bitrate in bits per second to end up with the given file size
+fn target_bitrate(megabytes: u64, duration: u32) -> u64 {
+    /// The audio is copied. WDR provides about 128 kbit/s
+    const AUDIO_BITRATE: u64 = 128_000;
+    /// Below this the video is unwatchable anyway
+    const MIN_BITRATE: u64 = 100_000;
+
+    let bits = megabytes * 1024 * 1024 * 8;
+    let total = bits / u64::from(duration.max(1));
+    total.saturating_sub(AUDIO_BITRATE).max(MIN_BITRATE)
+}
+
+/// Remux with the cover as attached picture.
+/// Not done while downloading as the extra input would confuse the stream selection of ffmpeg.
+fn attach_cover(video: &Path, cover: &Path) -> anyhow::Result<NamedTempFile> {
+    let output = get_tempfile(".mp4")?;
+    let mut command = ffmpeg();
+    command
+        .arg("-i")
+        .arg(video.as_os_str())
+        .arg("-i")
+        .arg(cover.as_os_str())
+        .args(["-map", "0", "-map", "1"])
+        .args(["-c", "copy"])
+        .args(["-disposition:v:1", "attached_pic"])
+        .arg(output.path().as_os_str());
+    run_command(command)?;
+    Ok(output)
+}
+
+/// Split into parts of about the same duration.
+/// Cuts happen at keyframes so the parts vary a bit in size.
+pub fn split(input: &Path, parts: u32) -> anyhow::Result<(TempDir, Vec<PathBuf>)> {
+    let stats = VideoStats::load(input)?;
+    let segment_time = stats.duration / f64::from(parts);
+    let directory = get_tempdir()?;
+    let mut command = ffmpeg();
+    command
+        .arg("-i")
+        .arg(input.as_os_str())
+        // Without the cover, segments cant contain attached pictures
+        .args(["-map", "0:V", "-map", "0:a?", "-map", "0:s?"])
+        .args(["-c", "copy", "-map_metadata", "0"])
+        .args(["-f", "segment", "-segment_format", "mp4"])
+        .args(["-reset_timestamps", "1"])
+        .arg("-segment_time")
+        .arg(format!("{segment_time:.3}"))
+        .arg(directory.path().join("part%03d.mp4"));
+    run_command(command)?;
+
+    let mut paths = std::fs::read_dir(directory.path())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    Ok((directory, paths))
+}
+
+/// Re-encode the video stream with two-pass H.264 to end up with about the given size
+pub fn shrink(input: &Path, megabytes: u64) -> anyhow::Result<NamedTempFile> {
+    let stats = VideoStats::load(input)?;
+    let bitrate = target_bitrate(megabytes, stats.duration_seconds()).to_string();
+    let output = get_tempfile(".mp4")?;
+    let directory = get_tempdir()?;
+    let passlog = directory.path().join("passlog");
+
+    let mut command = ffmpeg();
+    command
+        .arg("-i")
+        .arg(input.as_os_str())
+        .args(["-map", "0:V:0", "-c:V", "libx264", "-b:V", &bitrate])
+        .args(["-preset", "medium", "-pass", "1", "-passlogfile"])
+        .arg(passlog.as_os_str())
+        .args(["-an", "-f", "null", "/dev/null"]);
+    run_command(command)?;
+
+    let mut command = ffmpeg();
+    command
+        .arg("-i")
+        .arg(input.as_os_str())
+        .args([
+            "-map", "0", "-c", "copy", "-c:V", "libx264", "-b:V", &bitrate,
+        ])
+        .args(["-preset", "medium", "-pass", "2", "-passlogfile"])
+        .arg(passlog.as_os_str())
+        .arg(output.path().as_os_str());
+    run_command(command)?;
+    Ok(output)
+}
+
 pub fn extract_video_thumbnail(input: &Path) -> anyhow::Result<NamedTempFile> {
     let output = get_tempfile(".jpg")?;
     let mut command = ffmpeg();
@@ -70,45 +485,96 @@ pub fn extract_video_thumbnail(input: &Path) -> anyhow::Result<NamedTempFile> {
     Ok(output)
 }
 
-pub struct VideoStats {
-    pub height: u32,
-    pub width: u32,
-    /// In seconds
-    pub duration: u32,
-}
-
-impl VideoStats {
-    pub fn load(path: &Path) -> anyhow::Result<Self> {
-        let output = Command::new("ffprobe")
-            .arg("-hide_banner")
-            .arg(path.as_os_str())
-            .output()
-            .expect("failed to execute ffprobe");
-        let output = String::from_utf8(output.stderr).expect("ffprobe provided non utf8 output");
-
-        let duration = {
-            let captures = regex!(r"Duration: (\d{2}):(\d{2}):(\d{2})\.")
-                .captures(&output)
-                .context("duration not found in ffprobe output")?;
-            let hours = captures.get(1).unwrap().as_str().parse::<u32>().unwrap();
-            let minutes = captures.get(2).unwrap().as_str().parse::<u32>().unwrap();
-            let seconds = captures.get(3).unwrap().as_str().parse::<u32>().unwrap();
-            (((hours * 60) + minutes) * 60) + seconds
-        };
+#[test]
+fn metadata_args() {
+    let metadata = Metadata {
+        title: "Die Sendung vom 21.11.2021",
+        date: NaiveDate::from_ymd_opt(2021, 11, 21),
+        comment: "mdb-2580812".into(),
+        genre: "AktuelleSendung".into(),
+        cover: None,
+    };
+    let mut command = Command::new("ffmpeg");
+    metadata.args(&mut command);
+    let args = command
+        .get_args()
+        .map(|arg| arg.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        args,
+        [
+            "-metadata",
+            "title=Die Sendung vom 21.11.2021",
+            "-metadata",
+            "comment=mdb-2580812",
+            "-metadata",
+            "genre=AktuelleSendung",
+            "-metadata",
+            "date=2021-11-21",
+            "-metadata:s:s",
+            "language=deu",
+        ]
+    );
+}
 
-        let (width, height) = {
-            let captures = regex!(r", (\d+)x(\d+) \[")
-                .captures(&output)
-                .context("resolution not found in ffprobe output")?;
-            let width = captures.get(1).unwrap().as_str().parse().unwrap();
-            let height = captures.get(2).unwrap().as_str().parse().unwrap();
-            (width, height)
-        };
+#[test]
+fn video_args_works() {
+    assert!(video_args(&Profile::Copy, None).is_empty());
+    let profile = Profile::H264 {
+        crf: 23,
+        preset: "fast".into(),
+    };
+    assert_eq!(
+        video_args(&profile, None),
+        ["-c:v", "libx264", "-crf", "23", "-preset", "fast"]
+    );
+    let profile = Profile::TargetSize {
+        megabytes: 50,
+        preset: "medium".into(),
+    };
+    assert_eq!(
+        video_args(&profile, Some(1_000_000)),
+        ["-c:v", "libx264", "-b:v", "1000000", "-preset", "medium"]
+    );
+}
 
-        Ok(Self {
-            height,
-            width,
-            duration,
-        })
-    }
+#[test]
+fn target_bitrate_works() {
+    // 100 MiB in 10 minutes are about 1.4 Mbit/s in total
+    assert_eq!(target_bitrate(100, 600), 1_270_101);
+    // Tiny target results in the minimum
+    assert_eq!(target_bitrate(1, 3600), 100_000);
+}
+
+#[test]
+fn source_fits_works() {
+    let stats = VideoStats::parse(crate::ffprobe::EXAMPLE).unwrap();
+    let codecs = ["h264".to_owned()];
+    assert!(source_fits(&stats, &codecs, None));
+    assert!(source_fits(&stats, &codecs, Some(1000)));
+    assert!(!source_fits(&stats, &codecs, Some(500)));
+    assert!(!source_fits(&stats, &["hevc".to_owned()], None));
+}
+
+#[test]
+fn progress_works() {
+    let mut progress = Progress::default();
+    let block = "frame=9000\nfps=50.0\nout_time_us=360000000\nout_time=00:06:00.000000\nspeed=2.0x\nprogress=continue";
+    let completed = block.lines().filter(|line| progress.update(line)).count();
+    assert_eq!(completed, 1);
+    assert_eq!(
+        progress.status(Some(1800.0)),
+        "20% (6:00 / 30:00) at 2.0x, ETA 12:00"
+    );
+    assert_eq!(progress.status(None), "6:00 at 2.0x");
+
+    assert!(!progress.update("speed=N/A"));
+    assert_eq!(progress.status(Some(1800.0)), "20% (6:00 / 30:00)");
+}
+
+#[test]
+fn format_clock_works() {
+    assert_eq!(format_clock(5.4), "0:05");
+    assert_eq!(format_clock(754.0), "12:34");
+    assert_eq!(format_clock(3723.0), "1:02:03");
 }
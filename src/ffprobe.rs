@@ -0,0 +1,302 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Context as _;
+use serde::Deserialize;
+
+use crate::config;
+use crate::hls;
+
+/// Result of `ffprobe -show_streams -show_format`
+#[derive(Debug)]
+pub struct VideoStats {
+    /// In seconds
+    pub duration: f64,
+    /// Size of the container in bytes. Unknown for streams.
+    pub size: Option<u64>,
+    /// Overall bitrate in bits per second
+    pub bitrate: Option<u64>,
+    pub streams: Vec<Stream>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Stream {
+    pub index: u32,
+    pub kind: StreamKind,
+    /// Like `h264`, `aac` or `mov_text`
+    pub codec: Option<String>,
+    /// In bits per second
+    pub bitrate: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Frames per second
+    pub frame_rate: Option<f64>,
+    /// Audio channels
+    pub channels: Option<u32>,
+    pub language: Option<String>,
+    /// Cover art which is technically a video stream
+    pub attached_pic: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+    Attachment,
+    #[serde(other)]
+    Unknown,
+}
+
+impl VideoStats {
+    pub fn load<P: AsRef<OsStr>>(path: P) -> anyhow::Result<Self> {
+        let path = Path::new(path.as_ref());
+        let mut command = Command::new(&config::get().tools.ffprobe);
+        command
+            .args(["-v", "error"])
+            .args(["-print_format", "json"])
+            .arg("-show_streams")
+            .arg("-show_format")
+            .args(hls::input_args(path));
+        let output = command
+            .arg(path)
+            .output()
+            .expect("failed to execute ffprobe");
+        if !output.status.success() {
+            anyhow::bail!(
+                "ffprobe exited unsuccessfully: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let output = String::from_utf8(output.stdout).expect("ffprobe provided non utf8 output");
+        Self::parse(&output)
+    }
+
+    pub fn parse(json: &str) -> anyhow::Result<Self> {
+        let probe = serde_json::from_str::<Probe>(json).context("parse ffprobe output")?;
+        let duration = probe
+            .format
+            .duration
+            .as_deref()
+            .and_then(|duration| duration.parse().ok())
+            .context("duration not found in ffprobe output")?;
+        Ok(Self {
+            duration,
+            size: parse_number(probe.format.size.as_deref()),
+            bitrate: parse_number(probe.format.bit_rate.as_deref()),
+            streams: probe.streams.into_iter().map(Stream::from).collect(),
+        })
+    }
+
+    /// First video stream which is not an attached cover
+    pub fn video(&self) -> Option<&Stream> {
+        self.streams
+            .iter()
+            .find(|stream| stream.kind == StreamKind::Video && !stream.attached_pic)
+    }
+
+    pub fn audio(&self) -> Option<&Stream> {
+        self.streams
+            .iter()
+            .find(|stream| stream.kind == StreamKind::Audio)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.video()
+            .and_then(|video| video.width)
+            .unwrap_or_default()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.video()
+            .and_then(|video| video.height)
+            .unwrap_or_default()
+    }
+
+    /// Whole seconds like Telegram wants them
+    pub const fn duration_seconds(&self) -> u32 {
+        #[expect(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let seconds = self.duration.round() as u32;
+        seconds
+    }
+
+    /// File size in bytes. Estimated from the bitrate when the container size is unknown.
+    pub fn estimated_filesize(&self) -> Option<u64> {
+        self.size.or_else(|| {
+            #[expect(
+                clippy::cast_possible_truncation,
+                clippy::cast_sign_loss,
+                clippy::cast_precision_loss
+            )]
+            self.bitrate
+                .map(|bitrate| (bitrate as f64 / 8.0 * self.duration) as u64)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: ProbeFormat,
+}
+
+/// ffprobe provides most numbers as strings
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+    size: Option<String>,
+    bit_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    index: u32,
+    codec_type: StreamKind,
+    codec_name: Option<String>,
+    bit_rate: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    avg_frame_rate: Option<String>,
+    channels: Option<u32>,
+    #[serde(default)]
+    disposition: Disposition,
+    #[serde(default)]
+    tags: Tags,
+}
+
+#[derive(Default, Deserialize)]
+struct Disposition {
+    #[serde(default)]
+    attached_pic: u8,
+}
+
+#[derive(Default, Deserialize)]
+struct Tags {
+    language: Option<String>,
+}
+
+impl From<ProbeStream> for Stream {
+    fn from(stream: ProbeStream) -> Self {
+        Self {
+            index: stream.index,
+            kind: stream.codec_type,
+            codec: stream.codec_name,
+            bitrate: parse_number(stream.bit_rate.as_deref()),
+            width: stream.width,
+            height: stream.height,
+            frame_rate: stream.avg_frame_rate.as_deref().and_then(parse_frame_rate),
+            channels: stream.channels,
+            language: stream.tags.language,
+            attached_pic: stream.disposition.attached_pic != 0,
+        }
+    }
+}
+
+fn parse_number(value: Option<&str>) -> Option<u64> {
+    value?.parse().ok()
+}
+
+/// Frame rates are fractions like `25/1` or `30000/1001`. `0/0` is unknown.
+fn parse_frame_rate(value: &str) -> Option<f64> {
+    let (numerator, denominator) = value.split_once('/')?;
+    let numerator = numerator.parse::<f64>().ok()?;
+    let denominator = denominator.parse::<f64>().ok()?;
+    (denominator > 0.0 && numerator > 0.0).then(|| numerator / denominator)
+}
+
+#[cfg(test)]
+pub const EXAMPLE: &str = r#"{
+    "streams": [
+        {
+            "index": 0,
+            "codec_name": "h264",
+            "codec_type": "video",
+            "width": 1280,
+            "height": 720,
+            "r_frame_rate": "25/1",
+            "avg_frame_rate": "25/1",
+            "bit_rate": "3500000",
+            "disposition": { "default": 1, "attached_pic": 0 }
+        },
+        {
+            "index": 1,
+            "codec_name": "aac",
+            "codec_type": "audio",
+            "channels": 2,
+            "avg_frame_rate": "0/0",
+            "bit_rate": "128000",
+            "disposition": { "default": 1, "attached_pic": 0 },
+            "tags": { "language": "deu" }
+        },
+        {
+            "index": 2,
+            "codec_name": "mov_text",
+            "codec_type": "subtitle",
+            "avg_frame_rate": "0/0",
+            "disposition": { "default": 0, "attached_pic": 0 },
+            "tags": { "language": "deu" }
+        },
+        {
+            "index": 3,
+            "codec_name": "mjpeg",
+            "codec_type": "video",
+            "width": 1920,
+            "height": 1080,
+            "avg_frame_rate": "0/0",
+            "disposition": { "default": 0, "attached_pic": 1 }
+        }
+    ],
+    "format": {
+        "filename": "video.mp4",
+        "nb_streams": 4,
+        "format_name": "mov,mp4,m4a,3gp,3g2,mj2",
+        "duration": "1783.680000",
+        "size": "810123456",
+        "bit_rate": "3633462"
+    }
+}"#;
+
+#[test]
+fn parse_works() {
+    let stats = VideoStats::parse(EXAMPLE).unwrap();
+    assert!((stats.duration - 1783.68).abs() < f64::EPSILON);
+    assert_eq!(stats.duration_seconds(), 1784);
+    assert_eq!(stats.size, Some(810_123_456));
+    assert_eq!(stats.bitrate, Some(3_633_462));
+    assert_eq!(stats.streams.len(), 4);
+
+    let video = stats.video().unwrap();
+    assert_eq!(video.index, 0);
+    assert_eq!(video.codec.as_deref(), Some("h264"));
+    assert_eq!(video.frame_rate, Some(25.0));
+    assert_eq!((stats.width(), stats.height()), (1280, 720));
+
+    let audio = stats.audio().unwrap();
+    assert_eq!(audio.codec.as_deref(), Some("aac"));
+    assert_eq!(audio.channels, Some(2));
+    assert_eq!(audio.frame_rate, None);
+    assert_eq!(audio.language.as_deref(), Some("deu"));
+
+    assert_eq!(stats.streams[2].kind, StreamKind::Subtitle);
+    assert!(stats.streams[3].attached_pic);
+}
+
+#[test]
+fn estimated_filesize_from_bitrate() {
+    let json = r#"{"streams": [], "format": {"duration": "10.0", "bit_rate": "800000"}}"#;
+    let stats = VideoStats::parse(json).unwrap();
+    assert_eq!(stats.size, None);
+    assert_eq!(stats.estimated_filesize(), Some(1_000_000));
+}
+
+#[test]
+fn parse_frame_rate_works() {
+    assert_eq!(parse_frame_rate("25/1"), Some(25.0));
+    assert_eq!(parse_frame_rate("50/2"), Some(25.0));
+    assert_eq!(parse_frame_rate("0/0"), None);
+    assert_eq!(parse_frame_rate("nope"), None);
+}
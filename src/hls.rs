@@ -0,0 +1,258 @@
+use anyhow::Context as _;
+use retry::retry;
+use url::Url;
+
+use crate::config::Quality;
+
+/// Stream of a HLS master playlist (`#EXT-X-STREAM-INF`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    /// Bits per second
+    pub bandwidth: u64,
+    /// Width x Height
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+    pub uri: Url,
+}
+
+impl Variant {
+    fn has_video(&self) -> bool {
+        self.resolution.is_some()
+            || self.codecs.as_deref().is_none_or(|codecs| {
+                codecs
+                    .split(',')
+                    .any(|codec| !codec.trim().starts_with("mp4a"))
+            })
+    }
+
+    fn height(&self) -> u32 {
+        self.resolution.map_or(0, |(_, height)| height)
+    }
+}
+
+impl core::fmt::Display for Variant {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        #[expect(clippy::cast_precision_loss)]
+        let mbit = self.bandwidth as f64 / 1_000_000.0;
+        if let Some((width, height)) = self.resolution {
+            write!(fmt, "{width}x{height} ")?;
+        }
+        write!(fmt, "{mbit:.1} Mbit/s")?;
+        if let Some(codecs) = &self.codecs {
+            write!(fmt, " {codecs}")?;
+        }
+        Ok(())
+    }
+}
+
+/// HLS playlists are recognized by their extension
+pub fn is_playlist(path: &std::path::Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Input options for ffmpeg and ffprobe to read the given path.
+/// Segments of a local playlist created by [`segments::fetch`](crate::segments::fetch) might not have the usual extensions.
+pub fn input_args(path: &std::path::Path) -> &'static [&'static str] {
+    if path.is_file() && is_playlist(path) {
+        &["-allowed_extensions", "ALL"]
+    } else {
+        &[]
+    }
+}
+
+/// Select the variant of the master playlist according to the quality policy.
+/// Returns `None` when the url is not a HLS master playlist.
+pub fn select_variant(url: &Url, quality: &Quality) -> anyhow::Result<Option<Variant>> {
+    if !is_playlist(std::path::Path::new(url.path())) {
+        return Ok(None);
+    }
+    let master = get(url).context("HLS master playlist")?;
+    let variants = parse_master(url, &master)?;
+    if variants.is_empty() {
+        return Ok(None);
+    }
+    for variant in &variants {
+        println!("HLS variant {variant}");
+    }
+
+    let duration = if matches!(quality, Quality::MaxFilesize { .. }) {
+        let media = get(&variants[0].uri).context("HLS media playlist")?;
+        Some(media_duration(&media))
+    } else {
+        None
+    };
+
+    let variant = choose(variants, quality, duration).context("no HLS variant with video")?;
+    println!("HLS selected {variant}");
+    Ok(Some(variant))
+}
+
+fn get(url: &Url) -> anyhow::Result<String> {
+    retry(retry::delay::Exponential::from_millis(1000).take(3), || {
+        ureq::get(url.as_str())
+            .call()
+            .and_then(|response| response.into_body().read_to_string())
+    })
+    .map_err(anyhow::Error::msg)
+}
+
+/// Pick the best variant allowed by the quality policy.
+/// When none fits the policy the smallest one is taken.
+fn choose(variants: Vec<Variant>, quality: &Quality, duration: Option<f64>) -> Option<Variant> {
+    let mut variants = variants
+        .into_iter()
+        .filter(Variant::has_video)
+        .collect::<Vec<_>>();
+    variants.sort_by_key(|variant| (variant.bandwidth, variant.height()));
+    let fitting = variants
+        .iter()
+        .rposition(|variant| match quality {
+            Quality::Highest => true,
+            Quality::MaxHeight { height } => variant.height() <= *height,
+            Quality::MaxFilesize { megabytes } => {
+                #[expect(clippy::cast_precision_loss)]
+                let estimated = variant.bandwidth as f64 / 8.0 * duration.unwrap_or_default();
+                #[expect(clippy::cast_precision_loss)]
+                let limit = (*megabytes * 1024 * 1024) as f64;
+                estimated <= limit
+            }
+        })
+        .unwrap_or_default();
+    (!variants.is_empty()).then(|| variants.swap_remove(fitting))
+}
+
+pub fn parse_master(base: &Url, content: &str) -> anyhow::Result<Vec<Variant>> {
+    let mut variants = Vec::new();
+    let mut lines = content.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") else {
+            continue;
+        };
+        let uri = lines
+            .by_ref()
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .context("EXT-X-STREAM-INF without uri")?;
+
+        let mut bandwidth = None;
+        let mut resolution = None;
+        let mut codecs = None;
+        for (key, value) in parse_attributes(attributes) {
+            match key {
+                "BANDWIDTH" => bandwidth = Some(value.parse().context("BANDWIDTH")?),
+                "RESOLUTION" => {
+                    let (width, height) = value.split_once('x').context("RESOLUTION")?;
+                    resolution = Some((width.parse()?, height.parse()?));
+                }
+                "CODECS" => codecs = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        variants.push(Variant {
+            bandwidth: bandwidth.context("EXT-X-STREAM-INF without BANDWIDTH")?,
+            resolution,
+            codecs,
+            uri: base.join(uri)?,
+        });
+    }
+    Ok(variants)
+}
+
+/// Split `KEY=value,KEY="quoted, value"` into its key value pairs
+fn parse_attributes(attributes: &str) -> Vec<(&str, &str)> {
+    let mut result = Vec::new();
+    let mut rest = attributes;
+    while let Some((key, after)) = rest.split_once('=') {
+        let (value, after) = after.strip_prefix('"').map_or_else(
+            || after.split_once(',').unwrap_or((after, "")),
+            |quoted| {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let (value, after) = quoted.split_at(end);
+                (value, after.trim_start_matches('"'))
+            },
+        );
+        result.push((key.trim(), value));
+        rest = after.trim_start_matches(',');
+    }
+    result
+}
+
+/// Sum of the segment durations (`#EXTINF`) of a media playlist in seconds
+pub fn media_duration(content: &str) -> f64 {
+    content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("#EXTINF:"))
+        .filter_map(|rest| rest.split(',').next()?.trim().parse::<f64>().ok())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = r#"#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-STREAM-INF:PROGRAM-ID=1,BANDWIDTH=3776000,RESOLUTION=1280x720,CODECS="avc1.4d401f,mp4a.40.2"
+https://example.com/i/video_720.mp4/index_0_av.m3u8
+#EXT-X-STREAM-INF:PROGRAM-ID=1,BANDWIDTH=1728000,RESOLUTION=640x360,CODECS="avc1.4d401e,mp4a.40.2"
+video_360.mp4/index_1_av.m3u8
+#EXT-X-STREAM-INF:PROGRAM-ID=1,BANDWIDTH=7264000,RESOLUTION=1920x1080,CODECS="avc1.640028,mp4a.40.2"
+video_1080.mp4/index_2_av.m3u8
+#EXT-X-STREAM-INF:PROGRAM-ID=1,BANDWIDTH=96000,CODECS="mp4a.40.2"
+audio.mp4/index_3_a.m3u8
+"#;
+
+    fn variants() -> Vec<Variant> {
+        let base = Url::parse("https://example.com/i/master.m3u8").unwrap();
+        parse_master(&base, MASTER).unwrap()
+    }
+
+    #[test]
+    fn parse_master_works() {
+        let variants = variants();
+        assert_eq!(variants.len(), 4);
+        assert_eq!(
+            variants[0],
+            Variant {
+                bandwidth: 3_776_000,
+                resolution: Some((1280, 720)),
+                codecs: Some("avc1.4d401f,mp4a.40.2".into()),
+                uri: Url::parse("https://example.com/i/video_720.mp4/index_0_av.m3u8").unwrap(),
+            }
+        );
+        assert_eq!(
+            variants[1].uri.as_str(),
+            "https://example.com/i/video_360.mp4/index_1_av.m3u8"
+        );
+        assert!(!variants[3].has_video());
+    }
+
+    #[test]
+    fn choose_highest() {
+        let variant = choose(variants(), &Quality::Highest, None).unwrap();
+        assert_eq!(variant.resolution, Some((1920, 1080)));
+    }
+
+    #[test]
+    fn choose_max_height() {
+        let variant = choose(variants(), &Quality::MaxHeight { height: 720 }, None).unwrap();
+        assert_eq!(variant.resolution, Some((1280, 720)));
+        let variant = choose(variants(), &Quality::MaxHeight { height: 100 }, None).unwrap();
+        assert_eq!(variant.resolution, Some((640, 360)));
+    }
+
+    #[test]
+    fn choose_max_filesize() {
+        // 30 minutes at 3.7 Mbit/s are about 810 MB, at 7.2 Mbit/s about 1.5 GB
+        let quality = Quality::MaxFilesize { megabytes: 1000 };
+        let variant = choose(variants(), &quality, Some(30.0 * 60.0)).unwrap();
+        assert_eq!(variant.resolution, Some((1280, 720)));
+    }
+
+    #[test]
+    fn media_duration_works() {
+        let media = "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10.000,\nsegment1.ts\n#EXTINF:9.5,\nsegment2.ts\n#EXT-X-ENDLIST\n";
+        assert!((media_duration(media) - 19.5).abs() < f64::EPSILON);
+    }
+}
@@ -5,6 +5,7 @@ use std::process::{Command, Stdio};
 use tempfile::NamedTempFile;
 use url::Url;
 
+use crate::config;
 use crate::temporary::get_tempfile;
 
 pub fn download_jpg(url: &Url) -> anyhow::Result<NamedTempFile> {
@@ -22,12 +23,13 @@ pub fn resize_to_tg_thumbnail(image: &Path) -> anyhow::Result<NamedTempFile> {
 
     let output = get_tempfile(".jpg")?;
 
-    let mut command = Command::new("nice");
+    let tools = &config::get().tools;
+    let mut command = Command::new(&tools.nice);
     command
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .arg("magick")
+        .arg(&tools.magick)
         .arg(image.as_os_str())
         .args(["-sampling-factor", "4:2:0"])
         .args(["-resize", "320x320>"])
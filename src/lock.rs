@@ -0,0 +1,345 @@
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+/// How often a held lock is marked as still in use
+const REFRESH_INTERVAL: Duration = Duration::from_mins(5);
+
+/// Who holds the lock. Stored in the lock file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Owner {
+    pub pid: u32,
+    pub hostname: String,
+    pub since: DateTime<Local>,
+    /// Updated periodically while the lock is held
+    pub refreshed: DateTime<Local>,
+}
+
+impl Owner {
+    fn me() -> Self {
+        let now = Local::now();
+        Self {
+            pid: std::process::id(),
+            hostname: hostname(),
+            since: now,
+            refreshed: now,
+        }
+    }
+
+    /// Same process, regardless of when it refreshed last
+    fn is_same(&self, other: &Self) -> bool {
+        self.pid == other.pid && self.hostname == other.hostname && self.since == other.since
+    }
+
+    /// The owner crashed or hangs. Processes on other hosts can only be judged by the age of the last refresh.
+    fn is_stale(&self, me: &Self, stale_after: TimeDelta) -> bool {
+        if me.refreshed - self.refreshed > stale_after {
+            return true;
+        }
+        // Containers sharing a hostname might all run as the same pid, so only another pid can be checked
+        self.hostname == me.hostname && self.pid != me.pid && !process_exists(self.pid)
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+    }
+
+    /// Write the lock file atomically. With `replace` an existing file is overwritten.
+    /// False when the file already exists and is not replaced.
+    fn write(&self, path: &Path, replace: bool) -> anyhow::Result<bool> {
+        let directory = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let mut file = tempfile::NamedTempFile::new_in(directory)?;
+        file.write_all(serde_yaml::to_string(self)?.as_bytes())?;
+        file.as_file().sync_all()?;
+        if replace {
+            file.persist(path)?;
+            return Ok(true);
+        }
+        match file.persist_noclobber(path) {
+            Ok(_) => Ok(true),
+            Err(error) if error.error.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Another instance works on the same state files
+#[derive(Debug)]
+pub struct Held {
+    pub path: PathBuf,
+    pub owner: Owner,
+}
+
+impl core::fmt::Display for Held {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            fmt,
+            "{} is held by pid {} on {} since {}, skipping",
+            self.path.display(),
+            self.owner.pid,
+            self.owner.hostname,
+            self.owner.since.format("%Y-%m-%d %H:%M")
+        )
+    }
+}
+
+impl std::error::Error for Held {}
+
+/// Advisory lock file which is refreshed while held and removed on drop
+pub struct InstanceLock {
+    path: PathBuf,
+    owner: Owner,
+    refresher: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+impl InstanceLock {
+    /// Create the lock file. Fails with [`Held`] when another instance holds a lock which is not stale.
+    pub fn acquire(path: &Path, stale_after: TimeDelta) -> anyhow::Result<Self> {
+        Self::acquire_with(path, stale_after, REFRESH_INTERVAL)
+    }
+
+    fn acquire_with(
+        path: &Path,
+        stale_after: TimeDelta,
+        refresh: Duration,
+    ) -> anyhow::Result<Self> {
+        let me = Owner::me();
+        if !me.write(path, false)? {
+            Self::remove_stale(path, &me, stale_after)?;
+            anyhow::ensure!(
+                me.write(path, false)?,
+                "{} was taken by another instance meanwhile",
+                path.display()
+            );
+        }
+
+        let (stop, stopped) = mpsc::channel();
+        let thread_path = path.to_path_buf();
+        let mut thread_owner = me.clone();
+        let handle = std::thread::spawn(move || {
+            while stopped.recv_timeout(refresh) == Err(RecvTimeoutError::Timeout) {
+                if let Err(error) = Self::refresh(&thread_path, &mut thread_owner) {
+                    eprintln!(
+                        "failed to refresh lock {}: {error:#}",
+                        thread_path.display()
+                    );
+                }
+            }
+        });
+        Ok(Self {
+            path: path.into(),
+            owner: me,
+            refresher: Some((stop, handle)),
+        })
+    }
+
+    fn remove_stale(path: &Path, me: &Owner, stale_after: TimeDelta) -> anyhow::Result<()> {
+        let read = std::fs::read_to_string(path)
+            .and_then(|content| Ok((content, std::fs::metadata(path)?.modified()?)));
+        let (content, modified) = match read {
+            Ok(read) => read,
+            // Removed meanwhile
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        if let Ok(owner) = serde_yaml::from_str::<Owner>(&content) {
+            if !owner.is_stale(me, stale_after) {
+                return Err(Held {
+                    path: path.into(),
+                    owner,
+                }
+                .into());
+            }
+        } else if me.since - DateTime::<Local>::from(modified) <= stale_after {
+            anyhow::bail!(
+                "{} is unreadable but was modified recently, skipping",
+                path.display()
+            );
+        }
+
+        // Another starter might have replaced the stale lock with its own meanwhile.
+        // Moving it aside is atomic, so only remove it when it is still the stale one.
+        let mut aside = path.as_os_str().to_owned();
+        aside.push(format!(".stale-{}", me.pid));
+        match std::fs::rename(path, &aside) {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        }
+        if std::fs::read_to_string(&aside).is_ok_and(|moved| moved == content) {
+            println!("remove stale lock {}", path.display());
+        } else if let Err(error) = std::fs::hard_link(&aside, path) {
+            eprintln!(
+                "failed to restore the lock {} of another instance: {error}",
+                path.display()
+            );
+        }
+        std::fs::remove_file(&aside)?;
+        Ok(())
+    }
+
+    /// Mark the lock as still in use unless another instance took it over meanwhile
+    fn refresh(path: &Path, owner: &mut Owner) -> anyhow::Result<()> {
+        let current = Owner::read(path);
+        anyhow::ensure!(
+            current.is_some_and(|current| current.is_same(owner)),
+            "lost to another instance"
+        );
+        owner.refreshed = Local::now();
+        owner.write(path, true)?;
+        Ok(())
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if let Some((stop, handle)) = self.refresher.take() {
+            drop(stop);
+            _ = handle.join();
+        }
+        if !Owner::read(&self.path).is_some_and(|owner| owner.is_same(&self.owner)) {
+            eprintln!(
+                "lock {} was taken over by another instance, keeping it",
+                self.path.display()
+            );
+            return;
+        }
+        if let Err(error) = std::fs::remove_file(&self.path) {
+            eprintln!("failed to remove lock {}: {error}", self.path.display());
+        }
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|hostname| hostname.trim().to_owned())
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+fn process_exists(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[test]
+fn stale_detection() {
+    let me = Owner::me();
+    let hour = TimeDelta::hours(1);
+    let other_host = Owner {
+        pid: 1,
+        hostname: "elsewhere".into(),
+        since: me.since - TimeDelta::hours(3),
+        refreshed: me.since - TimeDelta::minutes(30),
+    };
+    assert!(!other_host.is_stale(&me, hour));
+    assert!(other_host.is_stale(&me, TimeDelta::minutes(10)));
+
+    // Another container with the same hostname and pid
+    let same_pid = Owner {
+        since: me.since - TimeDelta::minutes(1),
+        refreshed: me.since - TimeDelta::minutes(1),
+        ..me.clone()
+    };
+    assert!(!same_pid.is_stale(&me, hour));
+    assert!(same_pid.is_stale(&me, TimeDelta::seconds(10)));
+
+    let crashed = Owner {
+        pid: u32::MAX,
+        ..same_pid
+    };
+    assert!(crashed.is_stale(&me, hour));
+}
+
+#[cfg(test)]
+fn elsewhere(refreshed: DateTime<Local>) -> Owner {
+    Owner {
+        pid: 1,
+        hostname: "elsewhere".into(),
+        since: refreshed,
+        refreshed,
+    }
+}
+
+#[test]
+fn held_elsewhere() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("instance.lock");
+    std::fs::write(
+        &path,
+        serde_yaml::to_string(&elsewhere(Local::now())).unwrap(),
+    )
+    .unwrap();
+    let error = InstanceLock::acquire(&path, TimeDelta::hours(1))
+        .err()
+        .unwrap();
+    assert!(error.is::<Held>());
+
+    std::fs::write(
+        &path,
+        serde_yaml::to_string(&elsewhere(Local::now() - TimeDelta::hours(2))).unwrap(),
+    )
+    .unwrap();
+    let lock = InstanceLock::acquire(&path, TimeDelta::hours(1)).unwrap();
+    let owner = Owner::read(&path).unwrap();
+    assert_eq!(owner.pid, std::process::id());
+    // The stale lock moved aside is gone
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    drop(lock);
+    assert!(!path.exists());
+}
+
+#[test]
+fn unreadable_lock_is_only_stale_when_old() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("instance.lock");
+    std::fs::write(&path, "").unwrap();
+    assert!(InstanceLock::acquire(&path, TimeDelta::hours(1)).is_err());
+    assert!(path.exists());
+
+    let old = std::time::SystemTime::now() - Duration::from_hours(2);
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(old)
+        .unwrap();
+    let lock = InstanceLock::acquire(&path, TimeDelta::hours(1)).unwrap();
+    drop(lock);
+}
+
+#[test]
+fn refreshed_while_held() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("instance.lock");
+    let lock =
+        InstanceLock::acquire_with(&path, TimeDelta::hours(1), Duration::from_millis(10)).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    let owner = Owner::read(&path).unwrap();
+    assert_eq!(owner.since, lock.owner.since);
+    assert!(owner.refreshed > owner.since);
+    drop(lock);
+    assert!(!path.exists());
+
+    // Taken over meanwhile: the new lock is kept
+    let lock = InstanceLock::acquire(&path, TimeDelta::hours(1)).unwrap();
+    std::fs::write(
+        &path,
+        serde_yaml::to_string(&elsewhere(Local::now())).unwrap(),
+    )
+    .unwrap();
+    drop(lock);
+    assert_eq!(Owner::read(&path).unwrap().pid, 1);
+}
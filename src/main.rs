@@ -1,179 +1,474 @@
-use std::fmt::Write as _;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use retry::retry;
+use chrono::TimeDelta;
 
+use clap::Parser as _;
+
+use crate::cli::{Cli, Command};
+use crate::config::Config;
+use crate::daily::Failure;
 use crate::downloaded::Downloaded;
-use crate::image::{download_jpg, resize_to_tg_thumbnail};
-use crate::scrape::{Scrape, Scraperesult};
-use crate::telegram::Telegram;
+use crate::lock::{Held, InstanceLock};
+use crate::process::handle_one;
+use crate::publisher::{Publisher, Publishers};
+use crate::quarantine::Quarantine;
+use crate::scrape::{Page, Scrape, Scraperesult, Source, Topic};
+use crate::wdr_media::WdrMedia;
 
+mod archive;
+mod captions;
+mod cli;
+mod config;
 mod daily;
 mod downloaded;
 mod ffmpeg;
+mod ffprobe;
+mod hls;
 mod image;
+mod lock;
+mod nfo;
+mod parallel;
+mod persist;
+mod pipeline;
+mod process;
+mod publisher;
+mod quarantine;
 mod scrape;
+mod segments;
 mod telegram;
 mod temporary;
 mod wdr_media;
 
-fn main() {
-    let tg = Telegram::new();
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = config::init()?;
+    let result = command(config, cli.command.unwrap_or(Command::Run));
+    report_alerts(config);
+    result
+}
 
+fn command(config: &Config, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Run => run(&Publishers::new(config)?),
+        Command::Once => iteration(&Publishers::new(config)?),
+        Command::Scrape {
+            source,
+            dry_run: true,
+        } => {
+            migrate_history()?;
+            scrape_dry_run(source)
+        }
+        Command::Scrape {
+            source,
+            dry_run: false,
+        } => {
+            let publisher = Publishers::new(config)?;
+            let _lock = lock(&publisher)?;
+            Downloaded::migrate()?;
+            scrape(&publisher, source)
+        }
+        Command::Download { url, topic } => {
+            let publisher = Publishers::new(config)?;
+            let _lock = lock(&publisher)?;
+            Downloaded::migrate()?;
+            download(&publisher, topic, url)
+        }
+        Command::ListDownloaded => {
+            migrate_history()?;
+            list_downloaded()
+        }
+        Command::Show { id } => {
+            migrate_history()?;
+            show(&id)
+        }
+        Command::Forget { id } => {
+            let _lock = acquire_lock()?;
+            Downloaded::migrate()?;
+            let removed = Downloaded::forget(&id)?;
+            anyhow::ensure!(removed > 0, "{id} is not in the downloaded list");
+            println!("forgot {removed} entries of {id}");
+            Ok(())
+        }
+        Command::CatchUp { quota } => {
+            let quota = quota.unwrap_or(config.pipeline.quota);
+            let publisher = Publishers::new(config)?;
+            let _lock = lock(&publisher)?;
+            Downloaded::migrate()?;
+            catch_up(&publisher, quota)
+        }
+        Command::ListQuarantined => {
+            list_quarantined();
+            Ok(())
+        }
+        Command::Release { id } => {
+            let _lock = acquire_lock()?;
+            anyhow::ensure!(Quarantine::release(&id), "{id} is not in the quarantine");
+            println!("released {id}");
+            Ok(())
+        }
+        Command::CheckTools => check_tools(),
+    }
+}
+
+/// Tell the meta channel about problems with the state files which were not reported yet
+fn report_alerts(config: &Config) {
+    let alerts = persist::take_alerts();
+    if alerts.is_empty() {
+        return;
+    }
+    match Publishers::new(config) {
+        Ok(publisher) => {
+            for alert in alerts {
+                publisher.failed(None, &alert);
+            }
+        }
+        Err(error) => {
+            eprintln!("failed to report state file problems: {error:#}");
+            for alert in alerts {
+                eprintln!("{alert}");
+            }
+        }
+    }
+}
+
+fn run(publisher: &dyn Publisher) -> anyhow::Result<()> {
     #[allow(clippy::never_loop)]
     loop {
         // Do not create load right on startup
         #[cfg(not(debug_assertions))]
         std::thread::sleep(Duration::from_mins(5));
 
-        if let Err(err) = iteration(&tg) {
-            println!("Iteration failed {err:#}");
-            tg.send_err(&format!("ERROR {err:#}"));
+        match iteration(publisher) {
+            Ok(()) => {}
+            // Already told by lock
+            Err(err) if err.is::<Held>() => println!("{err:#}"),
+            Err(err) => {
+                println!("Iteration failed {err:#}");
+                publisher.failed(None, &format!("{err:#}"));
+            }
         }
 
         #[cfg(debug_assertions)]
-        break;
+        return Ok(());
     }
 }
 
-fn iteration(tg: &Telegram) -> anyhow::Result<()> {
-    use crate::daily::{Daily, Job};
-    let mut daily = Daily::new();
-    if let Some(job) = daily.get_next() {
-        println!("\n\ndo {job:?}…");
-        let downloaded = Downloaded::new();
-        let all = match job {
-            Job::AktuelleSunday | Job::AktuelleCheckup => Scrape::get_aktuell(),
-            Job::SachgeschichteMorning | Job::SachgeschichteEvening => {
-                Scrape::get_sachgeschichten()?
-            }
-        };
-        let total = all.len();
-        println!("found {total} video links");
-        'scrape: for (i, scraperesults) in all.enumerate() {
-            if i.is_multiple_of(25) {
-                println!("{i:>4}/{total:<4} {job:?}");
+fn iteration(publisher: &dyn Publisher) -> anyhow::Result<()> {
+    let _lock = lock(publisher)?;
+    Downloaded::migrate()?;
+    let result = due_job(publisher);
+    for alert in persist::take_alerts() {
+        publisher.failed(None, &alert);
+    }
+    result
+}
+
+/// Only one instance may work on the state files at a time.
+/// The meta channel is told once while the lock is held elsewhere.
+fn lock(publisher: &dyn Publisher) -> anyhow::Result<InstanceLock> {
+    static NOTIFIED: AtomicBool = AtomicBool::new(false);
+    match acquire_lock() {
+        Ok(lock) => {
+            NOTIFIED.store(false, Ordering::Relaxed);
+            Ok(lock)
+        }
+        Err(err) => {
+            if err.is::<Held>() && !NOTIFIED.swap(true, Ordering::Relaxed) {
+                publisher.failed(None, &format!("{err:#}"));
             }
-            match scraperesults {
-                Ok(scraperesults) => {
-                    for scraperesult in scraperesults {
-                        if downloaded.was_downloaded(&scraperesult.media) {
-                            continue;
-                        }
-                        if let Err(error) = handle_one(tg, &scraperesult) {
-                            let title = &scraperesult.media.tracker_data.title;
-                            eprintln!("Failed to download {title:?}: {error:#}");
-                            tg.send_err(&format!("ERROR Failed to download {title:?}: {error:#}"));
-                            continue;
-                        }
-                        Downloaded::mark_downloaded(scraperesult.media);
-                        if !matches!(job, Job::AktuelleCheckup | Job::AktuelleSunday) {
-                            break 'scrape;
-                        }
+            Err(err)
+        }
+    }
+}
+
+fn acquire_lock() -> anyhow::Result<InstanceLock> {
+    let config = config::get();
+    let stale_after = TimeDelta::hours(config.lock.stale_hours.into());
+    InstanceLock::acquire(&config.paths.lock, stale_after)
+}
+
+/// Import the former `downloaded.yaml` for commands which only read the history.
+/// The lock is only taken when there is something to migrate.
+fn migrate_history() -> anyhow::Result<()> {
+    if !config::get().paths.downloaded.exists() {
+        return Ok(());
+    }
+    let _lock = acquire_lock()?;
+    Downloaded::migrate()
+}
+
+fn due_job(publisher: &dyn Publisher) -> anyhow::Result<()> {
+    let mut daily = daily::Daily::new();
+    if let Some(job) = daily.get_next() {
+        println!("\n\ndo {}…", job.name);
+        if let Err(error) = scrape(publisher, job.source) {
+            let name = &job.name;
+            // Reported by the caller
+            return Err(match daily.mark_failed(job, &format!("{error:#}")) {
+                Failure::RetryAt(next_run) => {
+                    println!("retry {name} at {next_run}");
+                    error
+                }
+                Failure::GaveUp { attempts } => {
+                    error.context(format!("job {name} gave up after {attempts} attempts"))
+                }
+            });
+        }
+        daily.mark_successful(job);
+    }
+    Ok(())
+}
+
+fn scrape(publisher: &dyn Publisher, source: Source) -> anyhow::Result<()> {
+    let config = config::get();
+    if source == Source::Sachgeschichten && config.pipeline.scheduled {
+        return catch_up(publisher, config.pipeline.quota);
+    }
+    let downloaded = Downloaded::new()?;
+    let quarantine = Quarantine::new();
+    let all = Scrape::get(source, &config::get().sources)?;
+    let total = all.len();
+    println!("found {total} video links");
+    download_new(
+        publisher,
+        source,
+        all,
+        |media| Ok(downloaded.was_downloaded(media)? || quarantine.is_quarantined(media)),
+        |scraperesult| {
+            let media = &scraperesult.media;
+            let publication = handle_one(publisher, scraperesult)
+                .map_err(|error| record_failure(media, error))?;
+            Downloaded::mark_downloaded(media, &publication)?;
+            Quarantine::release(&media.tracker_data.id);
+            Ok(())
+        },
+    )
+}
+
+/// Handle the scraped videos which were not downloaded yet.
+/// Sachgeschichten stop after the first successful one.
+fn download_new<I, W, H>(
+    publisher: &dyn Publisher,
+    source: Source,
+    all: I,
+    was_downloaded: W,
+    mut handle: H,
+) -> anyhow::Result<()>
+where
+    I: ExactSizeIterator<Item = anyhow::Result<Vec<Scraperesult>>>,
+    W: Fn(&WdrMedia) -> anyhow::Result<bool>,
+    H: FnMut(&Scraperesult) -> anyhow::Result<()>,
+{
+    let total = all.len();
+    'scrape: for (i, scraperesults) in all.enumerate() {
+        if i.is_multiple_of(25) {
+            println!("{i:>4}/{total:<4} {source:?}");
+        }
+        match scraperesults {
+            Ok(scraperesults) => {
+                for scraperesult in scraperesults {
+                    if was_downloaded(&scraperesult.media)? {
+                        continue;
+                    }
+                    if let Err(error) = handle(&scraperesult) {
+                        let title = &scraperesult.media.tracker_data.title;
+                        eprintln!("Failed to download {title:?}: {error:#}");
+                        publisher.failed(Some(&scraperesult), &format!("{error:#}"));
+                        continue;
+                    }
+                    if source == Source::Sachgeschichten {
+                        break 'scrape;
                     }
                 }
-                Err(error) => eprintln!("{error:#}"),
             }
+            Err(error) => eprintln!("{error:#}"),
         }
-        daily.mark_successful(job);
     }
     Ok(())
 }
 
-fn handle_one(tg: &Telegram, video: &Scraperesult) -> anyhow::Result<()> {
-    let topic = video.topic;
-    let img = &video.img;
-    let media = &video.media;
-    let title = &media.tracker_data.title;
-    let air_time = &media.tracker_data.air_time;
-    let video = media.media_resource.get_video();
-    let sl = media.media_resource.get_sl_video();
-    let mut caption_srt = media.media_resource.captions_hash.srt.as_ref();
-    println!(
-        "found {topic} to download {title}\nAir Time: {air_time:?}\nImage: {}\nVideo: {}\nSign Language: {:?}\nCaptions: {:?}",
-        img.as_str(),
-        video.as_str(),
-        sl.map(url::Url::as_str),
-        caption_srt.map(url::Url::as_str)
-    );
-
-    if caption_srt.is_some_and(|url| url.path().ends_with("deleted")) {
-        println!("Ignore caption as it ends with 'deleted'.");
-        caption_srt = None;
-    }
-
-    let public_caption = air_time.as_ref().map_or_else(
-        || format!("{title}\n#{topic}"),
-        |air_time| format!("{title}\n{air_time} #{topic}"),
-    );
-    let meta_msg = tg.send_begin(img, &public_caption)?;
-
-    let start = Instant::now();
-    let cover = download_jpg(img)?;
-    let cover_took = start.elapsed();
-    let cover_filesize = path_filesize_string(cover.path()).expect("cant read cover size");
-    println!(
-        "cover took {}  {cover_filesize}",
-        format_duration(cover_took)
-    );
-
-    let start = Instant::now();
-    let thumbnail = resize_to_tg_thumbnail(cover.path())?;
-    let thumbnail_took = start.elapsed();
-    let thumbnail_filesize =
-        path_filesize_string(thumbnail.path()).expect("cant read thumbnail size");
-    println!(
-        "thumbnail took {}  {thumbnail_filesize} / 200 kB",
-        format_duration(thumbnail_took)
-    );
-
-    let start = Instant::now();
-    let normal = ffmpeg::download(video, caption_srt)?;
-    let sl = if let Some(sl) = &sl {
-        Some(ffmpeg::download(sl, caption_srt)?)
-    } else {
-        None
+/// Work through the Sachgeschichten backlog with the pipeline
+fn catch_up(publisher: &dyn Publisher, quota: usize) -> anyhow::Result<()> {
+    let config = config::get();
+    let downloaded = Downloaded::new()?;
+    let quarantine = Quarantine::new();
+    let pages = Scrape::get(Source::Sachgeschichten, &config.sources)?.into_pages();
+    println!("found {} video links, quota {quota}", pages.len());
+    let stages = pipeline::Stages {
+        scrape: |page: Page| page.scrape(),
+        was_downloaded: |media: &WdrMedia| {
+            Ok(downloaded.was_downloaded(media)? || quarantine.is_quarantined(media))
+        },
+        prepare: |video: &Scraperesult| {
+            process::prepare(publisher, video).map_err(|error| record_failure(&video.media, error))
+        },
+        publish: |video: &Scraperesult, prepared| {
+            let publication = process::publish(publisher, video, &prepared)
+                .map_err(|error| record_failure(&video.media, error))?;
+            Downloaded::mark_downloaded(&video.media, &publication)?;
+            Quarantine::release(&video.media.tracker_data.id);
+            Ok(())
+        },
     };
-    let download_took = start.elapsed();
-    println!("download took {}", format_duration(download_took));
-
-    let normal_filesize = path_filesize_string(normal.path()).expect("cant read video size");
-    let sl_filesize = sl.as_ref().map_or_else(
-        || "nope :(".into(),
-        |sl| path_filesize_string(sl.path()).expect("cant read video size"),
-    );
-    println!("Filesizes   Normal: {normal_filesize}   DGS: {sl_filesize}");
-
-    let mut meta_caption = format!(
-        "{public_caption}\n\nCover: {cover_filesize}\nThumbnail: {thumbnail_filesize} / 200 kB\nNormal: {normal_filesize}\nDGS: {sl_filesize}\n\ndownload took {}\n",
-        format_duration(download_took)
-    );
-    retry(retry::delay::Fixed::from_millis(60_000).take(2), || {
-        tg.update_meta(meta_msg, &meta_caption)
-    })
-    .map_err(anyhow::Error::msg)?;
-
-    let start = Instant::now();
-    tg.send_public_result(
-        &public_caption,
-        cover.path(),
-        thumbnail.path(),
-        normal.path(),
-        sl.as_ref().map(tempfile::NamedTempFile::path),
-    )?;
-    let upload_took = start.elapsed();
-    println!("upload   took {}", format_duration(upload_took));
-
-    writeln!(meta_caption, "upload took {}", format_duration(upload_took)).unwrap();
-    retry(retry::delay::Fixed::from_millis(60_000).take(2), || {
-        tg.update_meta(meta_msg, &meta_caption)
-    })
-    .map_err(anyhow::Error::msg)?;
+    let amount = pipeline::run(publisher, &config.pipeline, quota, pages, &stages);
+    println!("published {amount} videos");
     Ok(())
 }
 
-fn path_filesize_string(path: &std::path::Path) -> anyhow::Result<String> {
-    Ok(format_filesize(path.metadata()?.len()))
+/// Remember the failure for the quarantine. Mentions when the video is skipped from now on.
+fn record_failure(media: &WdrMedia, error: anyhow::Error) -> anyhow::Error {
+    let failure = Quarantine::record(media, &format!("{error:#}"));
+    match failure.quarantined_until(&config::get().quarantine) {
+        Some(until) => error.context(format!(
+            "quarantined until {} after {} failures",
+            until.format("%Y-%m-%d %H:%M"),
+            failure.count
+        )),
+        None => error,
+    }
+}
+
+fn scrape_dry_run(source: Source) -> anyhow::Result<()> {
+    let downloaded = Downloaded::new()?;
+    let quarantine = Quarantine::new();
+    let all = Scrape::get(source, &config::get().sources)?;
+    println!("found {} video links", all.len());
+    let mut amount = 0;
+    for scraperesults in all {
+        match scraperesults {
+            Ok(scraperesults) => {
+                for scraperesult in scraperesults {
+                    if downloaded.was_downloaded(&scraperesult.media)?
+                        || quarantine.is_quarantined(&scraperesult.media)
+                    {
+                        continue;
+                    }
+                    amount += 1;
+                    let media = &scraperesult.media;
+                    println!(
+                        "{:<16} {:<15} score {} {:?} {}",
+                        scraperesult.topic,
+                        media.tracker_data.id,
+                        media.media_resource.score(),
+                        media.tracker_data.title,
+                        media.tracker_data.air_time.as_deref().unwrap_or_default(),
+                    );
+                }
+            }
+            Err(error) => eprintln!("{error:#}"),
+        }
+    }
+    println!("{amount} videos would be downloaded");
+    Ok(())
+}
+
+fn download(publisher: &dyn Publisher, topic: Topic, page: url::Url) -> anyhow::Result<()> {
+    for scraperesults in Scrape::from_page(topic, page) {
+        for scraperesult in scraperesults? {
+            let publication = handle_one(publisher, &scraperesult)?;
+            Downloaded::mark_downloaded(&scraperesult.media, &publication)?;
+        }
+    }
+    Ok(())
+}
+
+fn list_downloaded() -> anyhow::Result<()> {
+    let list = Downloaded::new()?.list()?;
+    for media in &list {
+        println!(
+            "{:<15} score {} {:?} {}",
+            media.tracker_data.id,
+            media.media_resource.score(),
+            media.tracker_data.title,
+            media.tracker_data.air_time.as_deref().unwrap_or_default(),
+        );
+    }
+    println!("{} entries", list.len());
+    Ok(())
+}
+
+fn show(id: &str) -> anyhow::Result<()> {
+    let publications = Downloaded::new()?.publications(id)?;
+    anyhow::ensure!(!publications.is_empty(), "{id} has no recorded publication");
+    for (media, publication) in publications {
+        println!(
+            "{:?} {}\n{}",
+            media.tracker_data.title,
+            media.tracker_data.air_time.as_deref().unwrap_or_default(),
+            serde_yaml::to_string(&publication)?
+        );
+    }
+    Ok(())
+}
+
+fn list_quarantined() {
+    let quarantine = Quarantine::new();
+    let settings = &config::get().quarantine;
+    for (id, failure) in quarantine.list() {
+        let until = failure.quarantined_until(settings).map_or_else(
+            || "not quarantined".to_owned(),
+            |until| format!("until {}", until.format("%Y-%m-%d %H:%M")),
+        );
+        println!(
+            "{id:<15} {} failures, last {} {until} {:?}\n                {}",
+            failure.count,
+            failure.timestamp.format("%Y-%m-%d %H:%M"),
+            failure.title,
+            failure.last_error,
+        );
+    }
+    println!("{} entries", quarantine.list().len());
+}
+
+fn check_tools() -> anyhow::Result<()> {
+    let tools = &config::get().tools;
+    let checks = [
+        (
+            "ffmpeg",
+            vec![tools.ffmpeg.as_os_str(), "-version".as_ref()],
+        ),
+        (
+            "ffprobe",
+            vec![tools.ffprobe.as_os_str(), "-version".as_ref()],
+        ),
+        (
+            "magick",
+            vec![tools.magick.as_os_str(), "-version".as_ref()],
+        ),
+        (
+            "nice",
+            vec![
+                tools.nice.as_os_str(),
+                "-n17".as_ref(),
+                tools.ffmpeg.as_os_str(),
+                "-version".as_ref(),
+            ],
+        ),
+    ];
+    let mut failed = 0;
+    for (name, args) in checks {
+        let output = std::process::Command::new(args[0])
+            .args(&args[1..])
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let version = stdout.lines().next().unwrap_or_default();
+                println!("ok    {name:<8} {version}");
+            }
+            Ok(output) => {
+                failed += 1;
+                println!("FAIL  {name:<8} exited with {}", output.status);
+            }
+            Err(err) => {
+                failed += 1;
+                println!("FAIL  {name:<8} {err}");
+            }
+        }
+    }
+    anyhow::ensure!(failed == 0, "{failed} tools are not usable");
+    Ok(())
 }
 
 fn format_duration(duration: Duration) -> String {
@@ -183,6 +478,50 @@ fn format_duration(duration: Duration) -> String {
     format!("{minutes:.0} min {seconds:.2} sec")
 }
 
+#[test]
+fn download_new_stops_sachgeschichten_after_first_success() {
+    use crate::publisher::tests::{Recorder, example};
+
+    let all = || {
+        vec![
+            Ok(vec![example("a")]),
+            Err(anyhow::anyhow!("page broken")),
+            Ok(vec![example("b"), example("c")]),
+            Ok(vec![example("d")]),
+        ]
+        .into_iter()
+    };
+    let was_downloaded = |media: &WdrMedia| Ok(media.tracker_data.id == "a");
+    let handle = |handled: &mut Vec<String>, video: &Scraperesult| {
+        let id = &video.media.tracker_data.id;
+        handled.push(id.clone());
+        anyhow::ensure!(id != "b", "b is broken");
+        Ok(())
+    };
+
+    let recorder = Recorder::default();
+    let mut handled = Vec::new();
+    download_new(
+        &recorder,
+        Source::Sachgeschichten,
+        all(),
+        was_downloaded,
+        |video| handle(&mut handled, video),
+    )
+    .unwrap();
+    assert_eq!(handled, ["b", "c"]);
+    assert_eq!(recorder.events(), ["failed b"]);
+
+    let recorder = Recorder::default();
+    let mut handled = Vec::new();
+    download_new(&recorder, Source::Aktuell, all(), was_downloaded, |video| {
+        handle(&mut handled, video)
+    })
+    .unwrap();
+    assert_eq!(handled, ["b", "c", "d"]);
+    assert_eq!(recorder.events(), ["failed b"]);
+}
+
 #[test]
 fn format_duration_works() {
     assert_eq!("0 min 42.00 sec", format_duration(Duration::from_secs(42)));
@@ -0,0 +1,161 @@
+use std::fmt::Write as _;
+
+use chrono::Datelike as _;
+
+use crate::scrape::Topic;
+use crate::wdr_media::TrackerData;
+
+/// Season and episode of a video with a known air date.
+/// The season is the year of the air date, the episode is the number of the tracker id (`mdb-2580812`).
+/// Several Sachgeschichten air on the same day, so the date alone would merge them into one episode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EpisodeNumber {
+    pub season: i32,
+    pub episode: u32,
+}
+
+impl EpisodeNumber {
+    pub fn new(tracker_data: &TrackerData) -> Option<Self> {
+        let date = tracker_data.air_date()?;
+        let episode = tracker_data
+            .id
+            .rsplit('-')
+            .next()
+            .and_then(|number| number.parse().ok())?;
+        Some(Self {
+            season: date.year(),
+            episode,
+        })
+    }
+}
+
+pub const fn show_title(topic: Topic) -> &'static str {
+    match topic {
+        Topic::AktuelleSendung => "Die Sendung mit der Maus",
+        Topic::Sachgeschichte => "Sachgeschichten",
+        Topic::Zukunft => "Zukunft",
+    }
+}
+
+/// `tvshow.nfo` as understood by Jellyfin and Kodi
+pub fn tvshow(topic: Topic) -> String {
+    let mut nfo = String::from(XML_HEADER);
+    nfo += "<tvshow>\n";
+    element(&mut nfo, "title", show_title(topic));
+    element(&mut nfo, "studio", "WDR");
+    element(&mut nfo, "genre", "Kinder");
+    nfo += "</tvshow>\n";
+    nfo
+}
+
+/// Episode `.nfo` as understood by Jellyfin and Kodi
+pub fn episode(topic: Topic, tracker_data: &TrackerData) -> String {
+    let mut nfo = String::from(XML_HEADER);
+    nfo += "<episodedetails>\n";
+    element(&mut nfo, "title", &tracker_data.title);
+    element(&mut nfo, "showtitle", show_title(topic));
+    if let Some(number) = EpisodeNumber::new(tracker_data) {
+        element(&mut nfo, "season", &number.season.to_string());
+        element(&mut nfo, "episode", &number.episode.to_string());
+    } else {
+        element(&mut nfo, "season", "0");
+    }
+    if let Some(date) = tracker_data.air_date() {
+        element(&mut nfo, "aired", &date.to_string());
+    }
+    element(&mut nfo, "genre", &topic.to_string());
+    writeln!(
+        nfo,
+        "  <uniqueid type=\"wdr\" default=\"true\">{}</uniqueid>",
+        escape(&tracker_data.id)
+    )
+    .unwrap();
+    nfo += "</episodedetails>\n";
+    nfo
+}
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n";
+
+fn element(nfo: &mut String, name: &str, content: &str) {
+    writeln!(nfo, "  <{name}>{}</{name}>", escape(content)).unwrap();
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn escape_works() {
+    assert_eq!(escape("Tom & Jerry <3"), "Tom &amp; Jerry &lt;3");
+    assert_eq!(escape(r#"Was ist "Zeit"?"#), "Was ist &quot;Zeit&quot;?");
+}
+
+#[test]
+fn episode_with_air_date() {
+    let tracker_data = TrackerData {
+        id: "mdb-2580812".into(),
+        air_time: Some("21.11.2021 09:30".into()),
+        title: "Die Sendung vom 21.11.2021".into(),
+    };
+    assert_eq!(
+        EpisodeNumber::new(&tracker_data),
+        Some(EpisodeNumber {
+            season: 2021,
+            episode: 2_580_812
+        })
+    );
+    assert_eq!(
+        episode(Topic::AktuelleSendung, &tracker_data),
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<episodedetails>
+  <title>Die Sendung vom 21.11.2021</title>
+  <showtitle>Die Sendung mit der Maus</showtitle>
+  <season>2021</season>
+  <episode>2580812</episode>
+  <aired>2021-11-21</aired>
+  <genre>AktuelleSendung</genre>
+  <uniqueid type="wdr" default="true">mdb-2580812</uniqueid>
+</episodedetails>
+"#
+    );
+}
+
+#[test]
+fn episode_without_air_date_is_special() {
+    let tracker_data = TrackerData {
+        id: "mdb-1407836".into(),
+        air_time: None,
+        title: "Lenas Sommer auf der Alpe".into(),
+    };
+    assert_eq!(EpisodeNumber::new(&tracker_data), None);
+    let nfo = episode(Topic::Sachgeschichte, &tracker_data);
+    assert!(nfo.contains("<season>0</season>"));
+    assert!(!nfo.contains("<episode>"));
+    assert!(!nfo.contains("<aired>"));
+}
+
+#[test]
+fn same_day_episodes_differ() {
+    let first = TrackerData {
+        id: "mdb-2580812".into(),
+        air_time: Some("21.11.2021 09:30".into()),
+        title: "Kerzen".into(),
+    };
+    let second = TrackerData {
+        id: "mdb-2580900".into(),
+        title: "Lebkuchen".into(),
+        ..first.clone()
+    };
+    assert_ne!(EpisodeNumber::new(&first), EpisodeNumber::new(&second));
+}
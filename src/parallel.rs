@@ -0,0 +1,61 @@
+use std::sync::Mutex;
+
+pub type Task<'a, T> = Box<dyn FnOnce() -> T + Send + 'a>;
+
+/// Run the tasks with at most `limit` of them at the same time.
+/// The results are in the same order as the tasks.
+pub fn run<T: Send>(limit: usize, tasks: Vec<Task<'_, T>>) -> Vec<T> {
+    let total = tasks.len();
+    let queue = Mutex::new(tasks.into_iter().enumerate());
+    let results = Mutex::new((0..total).map(|_| None).collect::<Vec<Option<T>>>());
+    std::thread::scope(|scope| {
+        for _ in 0..limit.clamp(1, total.max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let next = queue.lock().unwrap().next();
+                    let Some((index, task)) = next else {
+                        break;
+                    };
+                    let result = task();
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every task ran"))
+        .collect()
+}
+
+#[test]
+fn keeps_order_and_limit() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let running = AtomicUsize::new(0);
+    let max_running = AtomicUsize::new(0);
+    let tasks = (0..6_usize)
+        .map(|index| {
+            let running = &running;
+            let max_running = &max_running;
+            Box::new(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                index * 10
+            }) as Task<'_, usize>
+        })
+        .collect();
+    let results = run(2, tasks);
+    assert_eq!(results, [0, 10, 20, 30, 40, 50]);
+    assert!(max_running.load(Ordering::SeqCst) <= 2);
+}
+
+#[test]
+fn no_tasks() {
+    assert!(run::<()>(3, Vec::new()).is_empty());
+}
@@ -0,0 +1,133 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context as _;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Problems with the state files which did not stop the tool but should be reported
+static ALERTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn alert(message: String) {
+    eprintln!("{message}");
+    ALERTS.lock().unwrap().push(message);
+}
+
+/// The alerts since the last call
+pub fn take_alerts() -> Vec<String> {
+    std::mem::take(&mut *ALERTS.lock().unwrap())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// Read a YAML file. When it is broken it is moved aside to `*.broken` and the backup `*.bak` is used instead.
+/// None when there is neither a readable file nor a backup.
+pub fn read<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let content = std::fs::read_to_string(path).ok()?;
+    match serde_yaml::from_str(&content) {
+        Ok(value) => Some(value),
+        Err(error) => {
+            let broken = with_suffix(path, ".broken");
+            let moved = std::fs::rename(path, &broken);
+            let backup = with_suffix(path, ".bak");
+            let restored = std::fs::read_to_string(&backup)
+                .ok()
+                .and_then(|content| serde_yaml::from_str(&content).ok());
+            let outcome = match (&restored, moved) {
+                (_, Err(move_error)) => format!("moving it aside failed: {move_error}"),
+                (Some(_), Ok(())) => format!(
+                    "moved to {} and continued with {}",
+                    broken.display(),
+                    backup.display()
+                ),
+                (None, Ok(())) => format!(
+                    "moved to {} and started empty as there is no usable backup",
+                    broken.display()
+                ),
+            };
+            alert(format!("{} is broken ({error}), {outcome}", path.display()));
+            restored
+        }
+    }
+}
+
+/// Write a YAML file atomically: the content is written to a temporary file, synced to disk and renamed over the old file.
+/// The previous file is kept as `*.bak`.
+/// Failures are reported as alert instead of stopping the tool.
+pub fn write<T: Serialize>(path: &Path, value: &T) {
+    if let Err(error) = try_write(path, value) {
+        alert(format!("failed to write {}: {error:#}", path.display()));
+    }
+}
+
+fn try_write<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
+    let content = serde_yaml::to_string(value)?;
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    let mut file = tempfile::NamedTempFile::new_in(directory).context("create temporary file")?;
+    file.write_all(content.as_bytes())?;
+    file.as_file().sync_all()?;
+
+    if path.exists() {
+        let backup = with_suffix(path, ".bak");
+        let backup_temp = with_suffix(path, ".bak.tmp");
+        std::fs::copy(path, &backup_temp).context("copy backup")?;
+        std::fs::rename(&backup_temp, &backup).context("rotate backup")?;
+    }
+    file.persist(path).context("replace file")?;
+    // Persist the rename itself
+    File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+#[test]
+fn write_keeps_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.yaml");
+    assert_eq!(read::<Vec<u8>>(&path), None);
+
+    write(&path, &vec![1_u8]);
+    assert_eq!(read::<Vec<u8>>(&path), Some(vec![1]));
+    assert!(!dir.path().join("state.yaml.bak").exists());
+
+    write(&path, &vec![1_u8, 2]);
+    assert_eq!(read::<Vec<u8>>(&path), Some(vec![1, 2]));
+    assert_eq!(
+        read::<Vec<u8>>(&dir.path().join("state.yaml.bak")),
+        Some(vec![1])
+    );
+}
+
+#[test]
+fn broken_file_falls_back_to_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.yaml");
+    write(&path, &vec![1_u8]);
+    write(&path, &vec![1_u8, 2]);
+    std::fs::write(&path, "- 1\n- [").unwrap();
+
+    assert_eq!(read::<Vec<u8>>(&path), Some(vec![1]));
+    assert!(dir.path().join("state.yaml.broken").exists());
+    assert!(
+        take_alerts()
+            .iter()
+            .any(|alert| alert.contains("state.yaml is broken"))
+    );
+
+    // The good backup is not replaced by the broken file
+    write(&path, &vec![3_u8]);
+    assert_eq!(
+        read::<Vec<u8>>(&dir.path().join("state.yaml.bak")),
+        Some(vec![1])
+    );
+}
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, sync_channel};
+
+use crate::config;
+use crate::publisher::Publisher;
+use crate::scrape::Scraperesult;
+use crate::wdr_media::WdrMedia;
+
+/// The work done by the stages of the pipeline
+pub struct Stages<S, W, P, U> {
+    /// Scrape the videos of a page
+    pub scrape: S,
+    pub was_downloaded: W,
+    /// Download and encode a video
+    pub prepare: P,
+    /// Upload a prepared video and remember it as downloaded
+    pub publish: U,
+}
+
+/// Work through the pages with separate scrape, download and upload stages.
+/// The stages are connected by bounded queues so the scraping does not run away from the encoding.
+/// At most `quota` videos are handed to the download stage.
+/// Returns the amount of published videos.
+pub fn run<Page, T, S, W, P, U>(
+    publisher: &dyn Publisher,
+    settings: &config::Pipeline,
+    quota: usize,
+    pages: Vec<Page>,
+    stages: &Stages<S, W, P, U>,
+) -> usize
+where
+    Page: Send,
+    T: Send,
+    S: Fn(Page) -> anyhow::Result<Vec<Scraperesult>> + Sync,
+    W: Fn(&WdrMedia) -> anyhow::Result<bool> + Sync,
+    P: Fn(&Scraperesult) -> anyhow::Result<T> + Sync,
+    U: Fn(&Scraperesult, T) -> anyhow::Result<()> + Sync,
+{
+    let total = pages.len();
+    let pages = Mutex::new(pages.into_iter().enumerate());
+    let admitted = AtomicUsize::new(0);
+    let seen = Mutex::new(HashSet::new());
+    let finished = AtomicUsize::new(0);
+
+    let (to_download, downloads) = sync_channel::<Scraperesult>(settings.queue_size);
+    let (to_upload, uploads) = sync_channel::<(Scraperesult, T)>(settings.queue_size);
+    let downloads = Mutex::new(downloads);
+    let uploads = Mutex::new(uploads);
+
+    std::thread::scope(|scope| {
+        for _ in 0..settings.scrape_workers {
+            let to_download = to_download.clone();
+            let (pages, admitted, seen) = (&pages, &admitted, &seen);
+            scope.spawn(move || {
+                while admitted.load(Ordering::SeqCst) < quota {
+                    let next = pages.lock().unwrap().next();
+                    let Some((i, page)) = next else {
+                        break;
+                    };
+                    if i.is_multiple_of(25) {
+                        println!("{i:>4}/{total:<4} pipeline");
+                    }
+                    let scraperesults = match (stages.scrape)(page) {
+                        Ok(scraperesults) => scraperesults,
+                        Err(error) => {
+                            eprintln!("{error:#}");
+                            continue;
+                        }
+                    };
+                    for scraperesult in scraperesults {
+                        let media = &scraperesult.media;
+                        let was_downloaded =
+                            (stages.was_downloaded)(media).unwrap_or_else(|error| {
+                                // Skipped for now, the next run checks again
+                                eprintln!("{error:#}");
+                                true
+                            });
+                        if was_downloaded
+                            || !seen.lock().unwrap().insert(media.tracker_data.id.clone())
+                        {
+                            continue;
+                        }
+                        if !take_slot(admitted, quota) {
+                            return;
+                        }
+                        if to_download.send(scraperesult).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        drop(to_download);
+
+        for _ in 0..settings.download_workers {
+            let to_upload = to_upload.clone();
+            let downloads = &downloads;
+            scope.spawn(move || {
+                while let Some(video) = receive(downloads) {
+                    match (stages.prepare)(&video) {
+                        Ok(prepared) => {
+                            if to_upload.send((video, prepared)).is_err() {
+                                return;
+                            }
+                        }
+                        Err(error) => report(publisher, &video, &error),
+                    }
+                }
+            });
+        }
+        drop(to_upload);
+
+        for _ in 0..settings.upload_workers {
+            let (uploads, finished) = (&uploads, &finished);
+            scope.spawn(move || {
+                while let Some((video, prepared)) = receive(uploads) {
+                    match (stages.publish)(&video, prepared) {
+                        Ok(()) => {
+                            finished.fetch_add(1, Ordering::SeqCst);
+                        }
+                        Err(error) => report(publisher, &video, &error),
+                    }
+                }
+            });
+        }
+    });
+
+    finished.into_inner()
+}
+
+/// Reserve one of the quota slots. False when the quota is used up.
+fn take_slot(admitted: &AtomicUsize, quota: usize) -> bool {
+    admitted
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |admitted| {
+            (admitted < quota).then_some(admitted + 1)
+        })
+        .is_ok()
+}
+
+/// Next item of a queue shared between the workers of a stage. None when the stage before is done.
+fn receive<T>(queue: &Mutex<Receiver<T>>) -> Option<T> {
+    queue.lock().unwrap().recv().ok()
+}
+
+fn report(publisher: &dyn Publisher, video: &Scraperesult, error: &anyhow::Error) {
+    let title = &video.media.tracker_data.title;
+    eprintln!("Failed to download {title:?}: {error:#}");
+    publisher.failed(Some(video), &format!("{error:#}"));
+}
+
+#[test]
+fn pipeline_respects_quota_and_skips_known() {
+    use crate::publisher::tests::{Recorder, example};
+
+    let pages = vec![
+        vec!["a", "b"],
+        vec!["b", "c", "d"],
+        vec!["e", "f"],
+        vec!["g"],
+    ];
+    let settings = config::Pipeline {
+        scrape_workers: 1,
+        download_workers: 2,
+        upload_workers: 1,
+        queue_size: 1,
+        ..config::Pipeline::default()
+    };
+    let published = Mutex::new(Vec::new());
+    let stages = Stages {
+        scrape: |ids: Vec<&str>| Ok(ids.into_iter().map(example).collect()),
+        was_downloaded: |media: &WdrMedia| Ok(media.tracker_data.id == "a"),
+        prepare: |video: &Scraperesult| {
+            let id = video.media.tracker_data.id.clone();
+            anyhow::ensure!(id != "c", "c is broken");
+            Ok(id)
+        },
+        publish: |_video: &Scraperesult, id: String| {
+            published.lock().unwrap().push(id);
+            Ok(())
+        },
+    };
+
+    let recorder = Recorder::default();
+    let amount = run(&recorder, &settings, 4, pages, &stages);
+    let mut published = published.into_inner().unwrap();
+    published.sort();
+
+    // b, c, d and e are admitted, c fails
+    assert_eq!(amount, 3);
+    assert_eq!(published, ["b", "d", "e"]);
+    assert_eq!(recorder.events(), ["failed c"]);
+}
@@ -0,0 +1,470 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+use sha2::{Digest as _, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::captions::{self, Subtitle};
+use crate::downloaded::{Downloaded, Publication, PublishedFile};
+use crate::ffprobe::VideoStats;
+use crate::image::{download_jpg, resize_to_tg_thumbnail};
+use crate::publisher::{Files, Publisher, Receipt, Upgrade};
+use crate::scrape::{Scraperesult, Topic};
+use crate::wdr_media::{MediaResources, WdrMedia};
+use crate::{config, ffmpeg, format_duration, format_filesize, hls, parallel};
+
+pub fn handle_one(publisher: &dyn Publisher, video: &Scraperesult) -> anyhow::Result<Publication> {
+    let prepared = prepare(publisher, video)?;
+    publish(publisher, video, &prepared)
+}
+
+/// A video which is downloaded and encoded but not yet published
+pub struct Prepared {
+    cover: NamedTempFile,
+    thumbnail: NamedTempFile,
+    subtitle: Option<Subtitle>,
+    profiles: Vec<UsedProfile>,
+    /// One file per encoding profile
+    normal: Vec<NamedTempFile>,
+    sl: Option<Vec<NamedTempFile>>,
+    ad: Option<Vec<NamedTempFile>>,
+    /// Summary for the publishers
+    status: String,
+    started: DateTime<Local>,
+    upgrade: Option<Upgrade>,
+    /// Details of the files, known before posting so recording the publication can not fail afterwards
+    files: Vec<PublishedFile>,
+}
+
+/// Download, encode and prepare everything to publish the video
+pub fn prepare(publisher: &dyn Publisher, video: &Scraperesult) -> anyhow::Result<Prepared> {
+    let topic = video.topic;
+    let img = &video.img;
+    let media = &video.media;
+    let title = &media.tracker_data.title;
+    let air_time = &media.tracker_data.air_time;
+    let normal = media.media_resource.get_video();
+    let sl = media.media_resource.get_sl_video();
+    let ad = media.media_resource.get_ad_video();
+    let captions = &media.media_resource.captions_hash;
+    println!(
+        "found {topic} to download {title}\nAir Time: {air_time:?}\nImage: {}\nVideo: {}\nSign Language: {:?}\nAudio Description: {:?}\nCaptions: {:?}",
+        img.as_str(),
+        normal.as_str(),
+        sl.map(url::Url::as_str),
+        ad.map(url::Url::as_str),
+        captions.available().collect::<Vec<_>>(),
+    );
+
+    let started = Local::now();
+    publisher.started(video)?;
+
+    let (cover, cover_filesize) = cover(img)?;
+
+    let subtitle = captions::prepare(captions);
+    let subtitle_path = subtitle.as_ref().map(|subtitle| subtitle.file.path());
+    let subtitle_format = subtitle.as_ref().map_or_else(
+        || "nope :(".into(),
+        |subtitle| format!("{:?}", subtitle.source),
+    );
+
+    let metadata = ffmpeg::Metadata {
+        title,
+        date: media.tracker_data.air_date(),
+        comment: format!("{}\n{}", video.caption(), media.tracker_data.id),
+        genre: topic.to_string(),
+        cover: Some(cover.path()),
+    };
+
+    let (variants, variant) = select_variants(normal, sl, ad)?;
+    let profiles = encoding_profiles(publisher, topic);
+
+    let start = Instant::now();
+    let (thumbnail, videos) = std::thread::scope(|scope| {
+        let thumbnail = scope.spawn(|| thumbnail(cover.path()));
+        let videos = download_variants(
+            publisher,
+            video,
+            &variants,
+            subtitle_path,
+            &metadata,
+            &profiles,
+        );
+        let thumbnail = thumbnail.join().expect("thumbnail thread panicked");
+        (thumbnail, videos)
+    });
+    let (thumbnail, thumbnail_filesize) = thumbnail?;
+    let mut videos = videos?.into_iter();
+    let normal = videos.next().expect("normal video is always downloaded");
+    let sl = sl.and_then(|_| videos.next());
+    let ad = ad.and_then(|_| videos.next());
+    let download_took = start.elapsed();
+    println!("download took {}", format_duration(download_took));
+
+    let normal_filesize = files_filesize_string(&normal);
+    let sl_filesize = sl
+        .as_deref()
+        .map_or_else(|| "nope :(".into(), files_filesize_string);
+    let ad_filesize = ad
+        .as_deref()
+        .map_or_else(|| "nope :(".into(), files_filesize_string);
+    println!("Filesizes   Normal: {normal_filesize}   DGS: {sl_filesize}   AD: {ad_filesize}");
+    let profile = profiles
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" / ");
+
+    let status = format!(
+        "Cover: {cover_filesize}\nThumbnail: {thumbnail_filesize} / 200 kB\nNormal: {normal_filesize}\nDGS: {sl_filesize}\nAD: {ad_filesize}\nCaptions: {subtitle_format}\nVariant: {variant}\nProfile: {profile}\n\ndownload took {}\n",
+        format_duration(download_took)
+    );
+    let files = published_files(&profiles, &normal, sl.as_deref(), ad.as_deref())?;
+    Ok(Prepared {
+        cover,
+        thumbnail,
+        subtitle,
+        profiles,
+        normal,
+        sl,
+        ad,
+        status,
+        started,
+        upgrade: find_upgrade(media),
+        files,
+    })
+}
+
+/// Hand the files to the publishers, once per encoding profile
+pub fn publish(
+    publisher: &dyn Publisher,
+    video: &Scraperesult,
+    prepared: &Prepared,
+) -> anyhow::Result<Publication> {
+    publisher.progress(video, &prepared.status)?;
+
+    let mut receipt = Receipt::default();
+    let mut errors = prepared
+        .profiles
+        .iter()
+        .enumerate()
+        .filter_map(|(index, used)| {
+            let result = publisher.finished(
+                video,
+                &Files {
+                    profile: &used.name,
+                    cover: prepared.cover.path(),
+                    thumbnail: prepared.thumbnail.path(),
+                    normal: prepared.normal[index].path(),
+                    sl: prepared.sl.as_ref().map(|sl| sl[index].path()),
+                    ad: prepared.ad.as_ref().map(|ad| ad[index].path()),
+                    captions: &video.media.media_resource.captions_hash,
+                    subtitle: prepared.subtitle.as_ref(),
+                    upgrade: prepared.upgrade.as_ref(),
+                },
+            );
+            result.map(|done| receipt.merge(done)).err()
+        })
+        .map(|error| format!("{error:#}"))
+        .collect::<Vec<_>>();
+    errors.append(&mut receipt.failures);
+    if !errors.is_empty() {
+        // Once the public post exists a retry would post it again
+        anyhow::ensure!(!receipt.public_messages.is_empty(), "{}", errors.join("\n"));
+        publisher.failed(
+            None,
+            &format!(
+                "{} was published but not everywhere:\n{}",
+                video.media.tracker_data.title,
+                errors.join("\n")
+            ),
+        );
+    }
+
+    Ok(Publication {
+        started: prepared.started,
+        published: Local::now(),
+        score: video.media.media_resource.score(),
+        receipt,
+        files: prepared.files.clone(),
+    })
+}
+
+/// The latest earlier publication of the video when this version has more variants.
+/// Falls back to entries without recorded publication so the meta channel gets the note at least.
+fn find_upgrade(media: &WdrMedia) -> Option<Upgrade> {
+    let history = Downloaded::new()
+        .and_then(|downloaded| downloaded.history(&media.tracker_data.id))
+        .unwrap_or_else(|error| {
+            eprintln!("failed to look up earlier publications: {error:#}");
+            Vec::new()
+        });
+    let earlier = history
+        .into_iter()
+        .filter(|(previous, _)| previous.tracker_data == media.tracker_data)
+        .collect::<Vec<_>>();
+    let (previous_media, previous) = earlier
+        .iter()
+        .rev()
+        .find(|(_, publication)| publication.is_some())
+        .or_else(|| earlier.last())?;
+    let note = upgrade_note(&previous_media.media_resource, &media.media_resource)?;
+    Some(Upgrade {
+        previous: previous
+            .as_ref()
+            .map(|publication| publication.receipt.clone())
+            .unwrap_or_default(),
+        note,
+    })
+}
+
+/// What the new version has in addition. None when nothing was added.
+fn upgrade_note(previous: &MediaResources, new: &MediaResources) -> Option<String> {
+    let added = [
+        (
+            previous.dflt.sl_video.is_none() && new.dflt.sl_video.is_some(),
+            "DGS",
+        ),
+        (
+            previous.dflt.ad_video.is_none() && new.dflt.ad_video.is_some(),
+            "Audiodeskription",
+        ),
+        (
+            previous.captions_hash.srt.is_none() && new.captions_hash.srt.is_some(),
+            "Untertiteln",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(added, name)| added.then_some(name))
+    .collect::<Vec<_>>();
+    match added.as_slice() {
+        [] => None,
+        [one] => Some(format!("Jetzt mit {one}")),
+        [rest @ .., last] => Some(format!("Jetzt mit {} und {last}", rest.join(", "))),
+    }
+}
+
+/// Size, hash and duration of every encoded file
+fn published_files(
+    profiles: &[UsedProfile],
+    normal: &[NamedTempFile],
+    sl: Option<&[NamedTempFile]>,
+    ad: Option<&[NamedTempFile]>,
+) -> anyhow::Result<Vec<PublishedFile>> {
+    let mut published = Vec::new();
+    for (index, used) in profiles.iter().enumerate() {
+        let variants = [("normal", Some(normal)), ("sl", sl), ("ad", ad)];
+        for (variant, files) in variants {
+            let Some(file) = files.map(|files| &files[index]) else {
+                continue;
+            };
+            let path = file.path();
+            published.push(PublishedFile {
+                profile: used.name.clone(),
+                variant: variant.to_owned(),
+                size: path.metadata()?.len(),
+                sha256: sha256(path)?,
+                duration: VideoStats::load(path).ok().map(|stats| stats.duration),
+            });
+        }
+    }
+    Ok(published)
+}
+
+fn sha256(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Download the cover. Also returns its filesize.
+fn cover(img: &url::Url) -> anyhow::Result<(NamedTempFile, String)> {
+    let start = Instant::now();
+    let cover = download_jpg(img)?;
+    let cover_took = start.elapsed();
+    let cover_filesize = path_filesize_string(cover.path()).expect("cant read cover size");
+    println!(
+        "cover took {}  {cover_filesize}",
+        format_duration(cover_took)
+    );
+    Ok((cover, cover_filesize))
+}
+
+/// Create the Telegram thumbnail from the cover. Also returns its filesize.
+fn thumbnail(cover: &Path) -> anyhow::Result<(NamedTempFile, String)> {
+    let start = Instant::now();
+    let thumbnail = resize_to_tg_thumbnail(cover)?;
+    let thumbnail_took = start.elapsed();
+    let thumbnail_filesize =
+        path_filesize_string(thumbnail.path()).expect("cant read thumbnail size");
+    println!(
+        "thumbnail took {}  {thumbnail_filesize} / 200 kB",
+        format_duration(thumbnail_took)
+    );
+    Ok((thumbnail, thumbnail_filesize))
+}
+
+/// Download and encode the variants at the same time, at most `parallel.variants` of them.
+/// Fails with the errors of all the failed variants.
+fn download_variants(
+    publisher: &dyn Publisher,
+    video: &Scraperesult,
+    variants: &[(&'static str, url::Url)],
+    subtitle: Option<&Path>,
+    metadata: &ffmpeg::Metadata,
+    profiles: &[UsedProfile],
+) -> anyhow::Result<Vec<Vec<NamedTempFile>>> {
+    let encodings = profiles
+        .iter()
+        .map(|used| used.profile.clone())
+        .collect::<Vec<_>>();
+    // Status of each variant to show them all at once
+    let statuses = Mutex::new(BTreeMap::new());
+    let report = |name: &'static str, status: &str| {
+        let mut statuses = statuses.lock().unwrap();
+        statuses.insert(name, status.to_owned());
+        let text = statuses
+            .iter()
+            .map(|(name, status)| format!("{name}: {status}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        drop(statuses);
+        publisher.encoding_progress(video, &text);
+    };
+
+    let tasks = variants
+        .iter()
+        .map(|(name, url)| {
+            let report = &report;
+            let encodings = &encodings;
+            Box::new(move || {
+                ffmpeg::download(url, subtitle, metadata, encodings, &|status| {
+                    report(name, status);
+                })
+            }) as parallel::Task<'_, _>
+        })
+        .collect();
+    let results = parallel::run(config::get().parallel.variants, tasks);
+
+    let mut files = Vec::with_capacity(results.len());
+    let mut errors = Vec::new();
+    for ((name, _), result) in variants.iter().zip(results) {
+        match result {
+            Ok(result) => files.push(result),
+            Err(error) => errors.push(format!("{name}: {error:#}")),
+        }
+    }
+    anyhow::ensure!(errors.is_empty(), "{}", errors.join("\n"));
+    Ok(files)
+}
+
+/// Encoding profile in use by some outputs
+struct UsedProfile {
+    name: String,
+    profile: config::Profile,
+    outputs: Vec<&'static str>,
+}
+
+impl core::fmt::Display for UsedProfile {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(fmt, "{} ({})", self.name, self.outputs.join(", "))
+    }
+}
+
+/// The encoding profiles of the outputs. Outputs with the same profile share the files.
+fn encoding_profiles(publisher: &dyn Publisher, topic: Topic) -> Vec<UsedProfile> {
+    let encoding = &config::get().encoding;
+    let mut profiles = Vec::<UsedProfile>::new();
+    for output in publisher.outputs() {
+        let name = encoding.profile_name(output, topic);
+        if let Some(used) = profiles.iter_mut().find(|used| used.name == name) {
+            used.outputs.push(output);
+        } else {
+            profiles.push(UsedProfile {
+                name: name.to_owned(),
+                profile: encoding
+                    .profile(name)
+                    .expect("profiles are validated with the config"),
+                outputs: vec![output],
+            });
+        }
+    }
+    profiles
+}
+
+/// The variants to download: normal and when available DGS and AD.
+/// Also returns the description of the selected normal variant.
+fn select_variants(
+    normal: &url::Url,
+    sl: Option<&url::Url>,
+    ad: Option<&url::Url>,
+) -> anyhow::Result<(Vec<(&'static str, url::Url)>, String)> {
+    let (normal, description) = select_variant(normal)?;
+    let mut variants = vec![("Normal", normal)];
+    if let Some(sl) = sl {
+        variants.push(("DGS", select_variant(sl)?.0));
+    }
+    if let Some(ad) = ad {
+        variants.push(("AD", select_variant(ad)?.0));
+    }
+    Ok((variants, description))
+}
+
+/// Resolve a HLS master playlist to the variant chosen by the quality policy.
+/// Returns the url to download and a description of the variant.
+fn select_variant(url: &url::Url) -> anyhow::Result<(url::Url, String)> {
+    let variant = hls::select_variant(url, &config::get().quality)?;
+    Ok(variant.map_or_else(
+        || (url.clone(), "default".into()),
+        |variant| {
+            let description = variant.to_string();
+            (variant.uri, description)
+        },
+    ))
+}
+
+fn path_filesize_string(path: &Path) -> anyhow::Result<String> {
+    Ok(format_filesize(path.metadata()?.len()))
+}
+
+/// Filesizes of the same video in different encoding profiles
+fn files_filesize_string(files: &[NamedTempFile]) -> String {
+    files
+        .iter()
+        .map(|file| path_filesize_string(file.path()).expect("cant read video size"))
+        .collect::<Vec<_>>()
+        .join(" / ")
+}
+
+#[test]
+fn upgrade_note_works() {
+    use url::Url;
+
+    use crate::publisher::tests::example;
+
+    let previous = example("a").media.media_resource;
+    assert_eq!(upgrade_note(&previous, &previous), None);
+
+    let mut new = previous.clone();
+    new.dflt.sl_video = Url::parse("https://edjopato.de").ok();
+    assert_eq!(
+        upgrade_note(&previous, &new).as_deref(),
+        Some("Jetzt mit DGS")
+    );
+    assert_eq!(upgrade_note(&new, &previous), None);
+
+    new.captions_hash.srt = Url::parse("https://edjopato.de").ok();
+    assert_eq!(
+        upgrade_note(&previous, &new).as_deref(),
+        Some("Jetzt mit DGS und Untertiteln")
+    );
+
+    new.dflt.ad_video = Url::parse("https://edjopato.de").ok();
+    assert_eq!(
+        upgrade_note(&previous, &new).as_deref(),
+        Some("Jetzt mit DGS, Audiodeskription und Untertiteln")
+    );
+}
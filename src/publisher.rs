@@ -0,0 +1,333 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::archive::Archive;
+use crate::captions::Subtitle;
+use crate::config::{self, Config};
+use crate::scrape::Scraperesult;
+use crate::telegram::Telegram;
+use crate::wdr_media::Captions;
+
+/// The finished files of a video
+pub struct Files<'a> {
+    /// Name of the encoding profile the videos were created with
+    pub profile: &'a str,
+    pub cover: &'a Path,
+    /// Small version of the cover, at most 320x320
+    pub thumbnail: &'a Path,
+    pub normal: &'a Path,
+    /// Deutsche Gebärdensprache
+    pub sl: Option<&'a Path>,
+    /// Audiodeskription
+    pub ad: Option<&'a Path>,
+    pub captions: &'a Captions,
+    /// The subtitle which is also part of the videos
+    pub subtitle: Option<&'a Subtitle>,
+    /// The video was published before with less variants
+    pub upgrade: Option<&'a Upgrade>,
+}
+
+/// An earlier publication of a video which is replaced by a version with more variants
+#[derive(Debug)]
+pub struct Upgrade {
+    /// Messages of the earlier publication. Empty when they were not recorded.
+    pub previous: Receipt,
+    /// Like `Jetzt mit DGS und Untertiteln`
+    pub note: String,
+}
+
+/// Messages a publisher created for a video. Empty for publishers without messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// Messages of the public post
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub public_messages: Vec<i32>,
+    /// Message with the status in the meta channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta_message: Option<i32>,
+    /// Publishers which failed while others succeeded, like `archive: <error>`
+    #[serde(skip)]
+    pub failures: Vec<String>,
+}
+
+impl Receipt {
+    pub fn merge(&mut self, other: Self) {
+        self.public_messages.extend(other.public_messages);
+        self.meta_message = self.meta_message.or(other.meta_message);
+        self.failures.extend(other.failures);
+    }
+}
+
+/// Destination of the downloaded videos
+///
+/// Gets notified about the lifecycle of every video: [`started`](Self::started),
+/// some [`progress`](Self::progress) and either [`finished`](Self::finished) or
+/// [`failed`](Self::failed).
+/// Variants of a video are processed in parallel, so it has to be [`Sync`].
+pub trait Publisher: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Names of the outputs this publishes to. Each of them can have its own encoding profile.
+    fn outputs(&self) -> Vec<&'static str> {
+        vec![self.name()]
+    }
+
+    /// The download of the video begins
+    fn started(&self, _video: &Scraperesult) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Human readable status while the video is processed
+    fn progress(&self, _video: &Scraperesult, _status: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Intermediate status of a long running step like encoding.
+    /// Only informative, so failures are not reported back.
+    fn encoding_progress(&self, _video: &Scraperesult, _status: &str) {}
+
+    /// All files are ready to be published.
+    /// Called once per encoding profile in use.
+    fn finished(&self, video: &Scraperesult, files: &Files) -> anyhow::Result<Receipt>;
+
+    /// Processing failed. Without video when the failure is not about a specific one.
+    fn failed(&self, _video: Option<&Scraperesult>, _error: &str) {}
+}
+
+/// All the enabled [`Publisher`]s
+pub struct Publishers(Vec<Box<dyn Publisher>>);
+
+impl Publishers {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let mut publishers = Vec::<Box<dyn Publisher>>::new();
+        if config.outputs.telegram {
+            publishers.push(Box::new(Telegram::new(&config.telegram)?));
+        }
+        if let Some(archive) = &config.outputs.archive {
+            publishers.push(Box::new(Archive::new(archive)?));
+        }
+        Ok(Self(publishers))
+    }
+
+    /// Run on every publisher even when one of them fails
+    fn each<F>(&self, mut action: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&dyn Publisher) -> anyhow::Result<()>,
+    {
+        let errors = self
+            .0
+            .iter()
+            .filter_map(|publisher| {
+                action(publisher.as_ref())
+                    .err()
+                    .map(|error| format!("{}: {error:#}", publisher.name()))
+            })
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(errors.join("\n")))
+        }
+    }
+}
+
+impl Publisher for Publishers {
+    fn name(&self) -> &'static str {
+        "all"
+    }
+
+    fn outputs(&self) -> Vec<&'static str> {
+        self.0
+            .iter()
+            .flat_map(|publisher| publisher.outputs())
+            .collect()
+    }
+
+    fn started(&self, video: &Scraperesult) -> anyhow::Result<()> {
+        self.each(|publisher| publisher.started(video))
+    }
+
+    fn progress(&self, video: &Scraperesult, status: &str) -> anyhow::Result<()> {
+        self.each(|publisher| publisher.progress(video, status))
+    }
+
+    fn encoding_progress(&self, video: &Scraperesult, status: &str) {
+        for publisher in &self.0 {
+            publisher.encoding_progress(video, status);
+        }
+    }
+
+    /// Failures of single publishers end up in [`Receipt::failures`] so the others are still recorded.
+    /// Only fails when every publisher of the profile failed.
+    fn finished(&self, video: &Scraperesult, files: &Files) -> anyhow::Result<Receipt> {
+        let encoding = &config::get().encoding;
+        let mut receipt = Receipt::default();
+        let mut succeeded = false;
+        for publisher in &self.0 {
+            if encoding.profile_name(publisher.name(), video.topic) != files.profile {
+                continue;
+            }
+            match publisher.finished(video, files) {
+                Ok(done) => {
+                    succeeded = true;
+                    receipt.merge(done);
+                }
+                Err(error) => receipt
+                    .failures
+                    .push(format!("{}: {error:#}", publisher.name())),
+            }
+        }
+        if !succeeded && !receipt.failures.is_empty() {
+            anyhow::bail!(receipt.failures.join("\n"));
+        }
+        Ok(receipt)
+    }
+
+    fn failed(&self, video: Option<&Scraperesult>, error: &str) {
+        for publisher in &self.0 {
+            publisher.failed(video, error);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use url::Url;
+
+    use super::*;
+    use crate::scrape::Topic;
+    use crate::wdr_media::{
+        Captions, MediaFormat, MediaResource, MediaResources, TrackerData, WdrMedia,
+    };
+
+    /// Remembers every event as `<event> <tracker id>`
+    #[derive(Default)]
+    pub struct Recorder {
+        pub events: Mutex<Vec<String>>,
+        pub fail_finished: bool,
+    }
+
+    impl Recorder {
+        pub fn events(&self) -> Vec<String> {
+            self.events.lock().unwrap().clone()
+        }
+
+        fn record(&self, event: &str, video: Option<&Scraperesult>) {
+            let id = video.map_or("-", |video| video.media.tracker_data.id.as_str());
+            self.events.lock().unwrap().push(format!("{event} {id}"));
+        }
+    }
+
+    impl Publisher for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn started(&self, video: &Scraperesult) -> anyhow::Result<()> {
+            self.record("started", Some(video));
+            Ok(())
+        }
+
+        fn progress(&self, video: &Scraperesult, _status: &str) -> anyhow::Result<()> {
+            self.record("progress", Some(video));
+            Ok(())
+        }
+
+        fn finished(&self, video: &Scraperesult, _files: &Files) -> anyhow::Result<Receipt> {
+            self.record("finished", Some(video));
+            anyhow::ensure!(!self.fail_finished, "finished failed");
+            Ok(Receipt::default())
+        }
+
+        fn failed(&self, video: Option<&Scraperesult>, _error: &str) {
+            self.record("failed", video);
+        }
+    }
+
+    impl<P: Publisher + ?Sized> Publisher for Arc<P> {
+        fn name(&self) -> &'static str {
+            self.as_ref().name()
+        }
+
+        fn started(&self, video: &Scraperesult) -> anyhow::Result<()> {
+            self.as_ref().started(video)
+        }
+
+        fn progress(&self, video: &Scraperesult, status: &str) -> anyhow::Result<()> {
+            self.as_ref().progress(video, status)
+        }
+
+        fn encoding_progress(&self, video: &Scraperesult, status: &str) {
+            self.as_ref().encoding_progress(video, status);
+        }
+
+        fn finished(&self, video: &Scraperesult, files: &Files) -> anyhow::Result<Receipt> {
+            self.as_ref().finished(video, files)
+        }
+
+        fn failed(&self, video: Option<&Scraperesult>, error: &str) {
+            self.as_ref().failed(video, error);
+        }
+    }
+
+    pub fn example(id: &str) -> Scraperesult {
+        let url = Url::parse("https://edjopato.de").unwrap();
+        let resource = MediaResource {
+            media_format: MediaFormat::Mp4,
+            video: url.clone(),
+            sl_video: None,
+            ad_video: None,
+        };
+        Scraperesult {
+            topic: Topic::Sachgeschichte,
+            img: url,
+            media: WdrMedia {
+                tracker_data: TrackerData {
+                    id: id.into(),
+                    air_time: None,
+                    title: "42".into(),
+                },
+                media_resource: MediaResources {
+                    preview_image: None,
+                    dflt: resource.clone(),
+                    alt: resource,
+                    captions_hash: Captions::default(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn publishers_notify_all_even_on_error() {
+        let failing = Arc::new(Recorder {
+            fail_finished: true,
+            ..Recorder::default()
+        });
+        let working = Arc::new(Recorder::default());
+        let publishers = Publishers(vec![Box::new(failing.clone()), Box::new(working.clone())]);
+        let video = example("a");
+        let path = Path::new("/dev/null");
+        let files = Files {
+            profile: "h265",
+            cover: path,
+            thumbnail: path,
+            normal: path,
+            sl: None,
+            ad: None,
+            captions: &Captions::default(),
+            subtitle: None,
+            upgrade: None,
+        };
+        let receipt = publishers.finished(&video, &files).unwrap();
+        assert_eq!(receipt.failures, ["recorder: finished failed"]);
+        assert_eq!(failing.events(), ["finished a"]);
+        assert_eq!(working.events(), ["finished a"]);
+
+        let publishers = Publishers(vec![Box::new(failing)]);
+        let error = publishers.finished(&video, &files).unwrap_err();
+        assert_eq!(error.to_string(), "recorder: finished failed");
+    }
+}
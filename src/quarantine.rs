@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, QuarantineConfig};
+use crate::persist;
+use crate::wdr_media::WdrMedia;
+
+/// Download workers record failures in parallel, each read-modify-write has to finish before the next starts
+static WRITE: Mutex<()> = Mutex::new(());
+
+/// Failures of a video which was not downloaded successfully yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    pub title: String,
+    pub count: u32,
+    pub last_error: String,
+    pub timestamp: DateTime<Local>,
+}
+
+impl Failure {
+    /// Time until which the video is skipped. None when below the threshold.
+    pub fn quarantined_until(&self, settings: &QuarantineConfig) -> Option<DateTime<Local>> {
+        (self.count >= settings.threshold)
+            .then(|| self.timestamp + TimeDelta::days(settings.days.into()))
+    }
+}
+
+/// Failures per tracker id
+pub struct Quarantine {
+    failures: BTreeMap<String, Failure>,
+}
+
+impl Quarantine {
+    #[must_use]
+    pub fn new() -> Self {
+        let failures = persist::read(&config::get().paths.quarantine).unwrap_or_default();
+        Self { failures }
+    }
+
+    pub fn is_quarantined(&self, media: &WdrMedia) -> bool {
+        self.is_quarantined_at(media, Local::now(), &config::get().quarantine)
+    }
+
+    fn is_quarantined_at(
+        &self,
+        media: &WdrMedia,
+        now: DateTime<Local>,
+        settings: &QuarantineConfig,
+    ) -> bool {
+        self.failures
+            .get(&media.tracker_data.id)
+            .and_then(|failure| failure.quarantined_until(settings))
+            .is_some_and(|until| now < until)
+    }
+
+    pub const fn list(&self) -> &BTreeMap<String, Failure> {
+        &self.failures
+    }
+
+    /// Remember a failed attempt. Returns the updated failure.
+    pub fn record(media: &WdrMedia, error: &str) -> Failure {
+        let _guard = WRITE.lock().unwrap();
+        let now = Local::now();
+        let mut failures = Self::new().failures;
+        let failure = failures
+            .entry(media.tracker_data.id.clone())
+            .or_insert_with(|| Failure {
+                title: media.tracker_data.title.clone(),
+                count: 0,
+                last_error: String::new(),
+                timestamp: now,
+            });
+        failure.count += 1;
+        error.clone_into(&mut failure.last_error);
+        failure.timestamp = now;
+        let failure = failure.clone();
+        Self::write(&failures);
+        failure
+    }
+
+    /// Forget the failures of the given tracker id. Returns false when there were none.
+    pub fn release(id: &str) -> bool {
+        let _guard = WRITE.lock().unwrap();
+        let mut failures = Self::new().failures;
+        let removed = failures.remove(id).is_some();
+        if removed {
+            Self::write(&failures);
+        }
+        removed
+    }
+
+    fn write(failures: &BTreeMap<String, Failure>) {
+        persist::write(&config::get().paths.quarantine, failures);
+    }
+}
+
+#[test]
+fn quarantined_after_threshold_for_period() {
+    use crate::publisher::tests::example;
+
+    let media = example("a").media;
+    let settings = QuarantineConfig {
+        threshold: 2,
+        days: 7,
+    };
+    let timestamp = Local::now();
+    let mut failure = Failure {
+        title: "42".into(),
+        count: 1,
+        last_error: "broken".into(),
+        timestamp,
+    };
+    let quarantine = |failure: &Failure| Quarantine {
+        failures: BTreeMap::from([("a".to_owned(), failure.clone())]),
+    };
+
+    assert_eq!(failure.quarantined_until(&settings), None);
+    assert!(!quarantine(&failure).is_quarantined_at(&media, timestamp, &settings));
+
+    failure.count = 2;
+    let until = timestamp + TimeDelta::days(7);
+    assert_eq!(failure.quarantined_until(&settings), Some(until));
+    let quarantine = quarantine(&failure);
+    assert!(quarantine.is_quarantined_at(&media, timestamp, &settings));
+    assert!(!quarantine.is_quarantined_at(&media, until, &settings));
+    assert!(!quarantine.is_quarantined_at(&example("b").media, timestamp, &settings));
+}
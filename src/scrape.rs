@@ -5,9 +5,10 @@ use lazy_regex::regex;
 use scraper::{ElementRef, Selector};
 use url::Url;
 
+use crate::config::Sources;
 use crate::wdr_media::WdrMedia;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize, clap::ValueEnum)]
 pub enum Topic {
     AktuelleSendung,
     Sachgeschichte,
@@ -20,6 +21,14 @@ impl core::fmt::Display for Topic {
     }
 }
 
+/// The pages a [`Scrape`] starts from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Aktuell,
+    Sachgeschichten,
+}
+
 fn get(url: &str) -> anyhow::Result<String> {
     let body = ureq::get(url).call()?.into_body().read_to_string()?;
     #[cfg(not(debug_assertions))]
@@ -33,31 +42,63 @@ pub struct Scraperesult {
     pub media: WdrMedia,
 }
 
+impl Scraperesult {
+    /// Caption of the public post: title, air time and topic hashtag
+    pub fn caption(&self) -> String {
+        let title = &self.media.tracker_data.title;
+        let topic = self.topic;
+        self.media.tracker_data.air_time.as_ref().map_or_else(
+            || format!("{title}\n#{topic}"),
+            |air_time| format!("{title}\n{air_time} #{topic}"),
+        )
+    }
+}
+
+/// A wdrmaus.de page containing videos
+pub struct Page {
+    pub topic: Topic,
+    pub url: Url,
+}
+
+impl Page {
+    pub fn scrape(&self) -> anyhow::Result<Vec<Scraperesult>> {
+        let Self { topic, url } = self;
+        get_from_page(*topic, url).with_context(|| format!("{topic} scrape {url} failed"))
+    }
+}
+
 pub struct Scrape {
-    links: Vec<(Topic, Url)>,
+    links: Vec<Page>,
 }
 
 impl Scrape {
-    pub fn get_aktuell() -> Self {
-        static AKTUELLE: LazyLock<Url> =
-            LazyLock::new(|| Url::parse("https://www.wdrmaus.de/aktuelle-sendung/").unwrap());
+    pub fn get(source: Source, sources: &Sources) -> anyhow::Result<Self> {
+        match source {
+            Source::Aktuell => Ok(Self::get_aktuell(sources)),
+            Source::Sachgeschichten => Self::get_sachgeschichten(sources),
+        }
+    }
+
+    /// Only the videos of the given page
+    pub fn from_page(topic: Topic, page: Url) -> Self {
         Self {
-            links: vec![(Topic::AktuelleSendung, AKTUELLE.clone())],
+            links: vec![Page { topic, url: page }],
         }
     }
 
-    pub fn get_sachgeschichten() -> anyhow::Result<Self> {
-        static SACHGESCHICHTEN: LazyLock<Url> = LazyLock::new(|| {
-            Url::parse("https://www.wdrmaus.de/filme/sachgeschichten/index.php5?filter=alle")
-                .unwrap()
-        });
-        static ZUKUNFT: LazyLock<Url> = LazyLock::new(|| {
-            Url::parse("https://www.wdrmaus.de/extras/mausthemen/zukunft/").unwrap()
-        });
+    fn get_aktuell(sources: &Sources) -> Self {
+        Self {
+            links: vec![Page {
+                topic: Topic::AktuelleSendung,
+                url: sources.aktuelle.clone(),
+            }],
+        }
+    }
 
+    fn get_sachgeschichten(sources: &Sources) -> anyhow::Result<Self> {
         let mut links = Vec::new();
-        links.append(&mut Self::get_linked(Topic::Sachgeschichte, &SACHGESCHICHTEN)?.links);
-        links.append(&mut Self::get_linked(Topic::Zukunft, &ZUKUNFT)?.links);
+        links.append(&mut Self::get_linked(Topic::Sachgeschichte, &sources.sachgeschichten)?.links);
+        links.append(&mut Self::get_linked(Topic::Zukunft, &sources.zukunft)?.links);
         Ok(Self { links })
     }
 
@@ -70,7 +111,7 @@ impl Scrape {
             .select(&LINK)
             .filter_map(|elem| elem.value().attr("href"))
             .filter_map(|href| base.join(href).ok())
-            .map(|url| (topic, url))
+            .map(|url| Page { topic, url })
             .rev() // Vec::pop starts at the end
             .collect::<Vec<_>>();
         anyhow::ensure!(!links.is_empty(), "no linked video pages");
@@ -80,19 +121,29 @@ impl Scrape {
     pub const fn len(&self) -> usize {
         self.links.len()
     }
+
+    /// The pages in the order the iterator would scrape them
+    pub fn into_pages(self) -> Vec<Page> {
+        let mut pages = self.links;
+        pages.reverse();
+        pages
+    }
 }
 
 impl Iterator for Scrape {
     type Item = anyhow::Result<Vec<Scraperesult>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (topic, link) = self.links.pop()?;
-        let scraperesult =
-            get_from_page(topic, &link).with_context(|| format!("{topic} scrape {link} failed"));
-        Some(scraperesult)
+        self.links.pop().map(|page| page.scrape())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.links.len(), Some(self.links.len()))
     }
 }
 
+impl ExactSizeIterator for Scrape {}
+
 fn get_from_page(topic: Topic, base: &Url) -> anyhow::Result<Vec<Scraperesult>> {
     fn from_container(base: &Url, videocontainer: ElementRef) -> anyhow::Result<(Url, WdrMedia)> {
         static IMG: LazyLock<Selector> = LazyLock::new(|| Selector::parse("img").unwrap());
@@ -0,0 +1,350 @@
+use std::fmt::Write as _;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context as _;
+use retry::retry;
+use sha2::{Digest as _, Sha256};
+use url::Url;
+
+use crate::config;
+use crate::hls;
+
+/// Work directories untouched for this long belong to videos which are not retried anymore
+const ABANDONED_AFTER: Duration = Duration::from_hours(30 * 24);
+
+/// HLS media playlist downloaded into the work directory
+pub struct LocalStream {
+    directory: PathBuf,
+    pub playlist: PathBuf,
+}
+
+impl LocalStream {
+    /// Only remove when the stream was processed successfully. Otherwise it's kept for resuming.
+    pub fn remove(self) {
+        if let Err(error) = std::fs::remove_dir_all(&self.directory) {
+            eprintln!(
+                "failed to remove work directory {}: {error}",
+                self.directory.display()
+            );
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Segment {
+    /// In seconds
+    duration: f64,
+    uri: Url,
+}
+
+#[derive(Debug, PartialEq)]
+struct MediaPlaylist {
+    target_duration: u32,
+    /// Initialization section (`#EXT-X-MAP`) of fragmented MP4 streams
+    map: Option<Url>,
+    segments: Vec<Segment>,
+}
+
+/// Download all segments of a HLS media playlist into the work directory.
+/// Already downloaded segments of an earlier attempt are reused.
+///
+/// Returns `None` when the url is not a playlist this can handle (no HLS, master playlist, encrypted)
+/// so ffmpeg has to take care of it.
+pub fn fetch(url: &Url) -> anyhow::Result<Option<LocalStream>> {
+    if !hls::is_playlist(Path::new(url.path())) {
+        return Ok(None);
+    }
+
+    let content = retry(retry::delay::Exponential::from_millis(1000).take(3), || {
+        ureq::get(url.as_str())
+            .call()
+            .and_then(|response| response.into_body().read_to_string())
+    })
+    .map_err(anyhow::Error::msg)
+    .context("HLS media playlist")?;
+    let Some(playlist) = parse_media(url, &content)? else {
+        println!("HLS playlist is not supported natively, leave it to ffmpeg");
+        return Ok(None);
+    };
+
+    // Only short sample for faster finish
+    #[cfg(debug_assertions)]
+    let playlist = {
+        let mut playlist = playlist;
+        let mut duration = 0.0;
+        playlist.segments.retain(|segment| {
+            duration += segment.duration;
+            duration - segment.duration < 10.0
+        });
+        playlist
+    };
+
+    let work = &config::get().paths.work;
+    remove_abandoned(work, SystemTime::now());
+    let directory = work.join(work_name(url));
+    std::fs::create_dir_all(&directory)
+        .with_context(|| format!("failed to create {}", directory.display()))?;
+
+    let existing = std::fs::read_dir(&directory)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_none_or(|ext| ext != "part"))
+        .count();
+    if existing > 0 {
+        println!(
+            "HLS resume with {existing} files in {}",
+            directory.display()
+        );
+    }
+
+    let map = playlist
+        .map
+        .as_ref()
+        .map(|map| -> anyhow::Result<String> {
+            let name = format!("init{}", extension(map, ".mp4"));
+            download_once(map, &directory.join(&name))?;
+            Ok(name)
+        })
+        .transpose()?;
+
+    let total = playlist.segments.len();
+    let mut names = Vec::with_capacity(total);
+    for (index, segment) in playlist.segments.iter().enumerate() {
+        let name = format!("segment{index:05}{}", extension(&segment.uri, ".ts"));
+        download_once(&segment.uri, &directory.join(&name))
+            .with_context(|| format!("HLS segment {index}/{total}"))?;
+        names.push(name);
+    }
+
+    let local = directory.join("index.m3u8");
+    std::fs::write(&local, local_playlist(&playlist, map.as_deref(), &names))?;
+    Ok(Some(LocalStream {
+        directory,
+        playlist: local,
+    }))
+}
+
+/// Directory name in the work directory which stays the same for the same stream, also across builds
+fn work_name(url: &Url) -> String {
+    let hash = format!("{:x}", Sha256::digest(url.as_str()));
+    format!("hls-{}", &hash[..16])
+}
+
+/// Remove the work directories of streams which were not touched for [`ABANDONED_AFTER`]
+fn remove_abandoned(work: &Path, now: SystemTime) {
+    let Ok(entries) = std::fs::read_dir(work) else {
+        return;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let is_stream = entry.file_name().to_string_lossy().starts_with("hls-");
+        let abandoned = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age > ABANDONED_AFTER);
+        if is_stream && abandoned {
+            let path = entry.path();
+            println!("remove abandoned work directory {}", path.display());
+            if let Err(error) = std::fs::remove_dir_all(&path) {
+                eprintln!("failed to remove {}: {error}", path.display());
+            }
+        }
+    }
+}
+
+fn extension(url: &Url, fallback: &'static str) -> String {
+    Path::new(url.path())
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map_or_else(|| fallback.to_owned(), |extension| format!(".{extension}"))
+}
+
+/// Download with retries unless the file already exists from an earlier attempt
+fn download_once(url: &Url, path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    let partial = path.with_extension("part");
+    retry(retry::delay::Exponential::from_millis(1000).take(5), || {
+        download(url, &partial)
+    })
+    .map_err(|error| anyhow::anyhow!("{error}"))
+    .with_context(|| format!("download {url}"))?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn download(url: &Url, path: &Path) -> anyhow::Result<()> {
+    let mut reader = ureq::get(url.as_str()).call()?.into_body().into_reader();
+    let file = std::fs::File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    std::io::copy(&mut reader, &mut writer)?;
+    Ok(())
+}
+
+/// Parse a media playlist.
+/// Returns `None` for master playlists and encrypted streams.
+fn parse_media(base: &Url, content: &str) -> anyhow::Result<Option<MediaPlaylist>> {
+    let mut target_duration = 10;
+    let mut map = None;
+    let mut segments = Vec::new();
+    let mut duration = None;
+    for line in content.lines().map(str::trim) {
+        if line.starts_with("#EXT-X-STREAM-INF") {
+            return Ok(None);
+        } else if let Some(key) = line.strip_prefix("#EXT-X-KEY:") {
+            if !key.contains("METHOD=NONE") {
+                return Ok(None);
+            }
+        } else if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            target_duration = value.parse().context("EXT-X-TARGETDURATION")?;
+        } else if let Some(attributes) = line.strip_prefix("#EXT-X-MAP:") {
+            let uri = attributes
+                .split_once("URI=\"")
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .context("EXT-X-MAP without URI")?
+                .0;
+            map = Some(base.join(uri)?);
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let value = value.split(',').next().unwrap_or_default().trim();
+            duration = Some(value.parse().context("EXTINF")?);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            segments.push(Segment {
+                duration: duration.take().context("segment without EXTINF")?,
+                uri: base.join(line)?,
+            });
+        }
+    }
+    anyhow::ensure!(!segments.is_empty(), "HLS media playlist without segments");
+    Ok(Some(MediaPlaylist {
+        target_duration,
+        map,
+        segments,
+    }))
+}
+
+fn local_playlist(playlist: &MediaPlaylist, map: Option<&str>, names: &[String]) -> String {
+    let mut local = String::from("#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-PLAYLIST-TYPE:VOD\n");
+    writeln!(local, "#EXT-X-TARGETDURATION:{}", playlist.target_duration).unwrap();
+    if let Some(map) = map {
+        writeln!(local, "#EXT-X-MAP:URI=\"{map}\"").unwrap();
+    }
+    for (segment, name) in playlist.segments.iter().zip(names) {
+        writeln!(local, "#EXTINF:{:.3},\n{name}", segment.duration).unwrap();
+    }
+    local += "#EXT-X-ENDLIST\n";
+    local
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEDIA: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-ALLOW-CACHE:YES
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-VERSION:3
+#EXT-X-MEDIA-SEQUENCE:1
+#EXTINF:10.000,
+segment1_0_av.ts?null=0
+#EXTINF:10.000,
+https://cdn.example.com/segment2_0_av.ts
+#EXTINF:4.480,
+segment3_0_av.ts
+#EXT-X-ENDLIST
+";
+
+    fn base() -> Url {
+        Url::parse("https://example.com/i/video.mp4/index_0_av.m3u8").unwrap()
+    }
+
+    #[test]
+    fn parse_media_works() {
+        let playlist = parse_media(&base(), MEDIA).unwrap().unwrap();
+        assert_eq!(playlist.target_duration, 10);
+        assert_eq!(playlist.map, None);
+        assert_eq!(playlist.segments.len(), 3);
+        assert_eq!(
+            playlist.segments[0].uri.as_str(),
+            "https://example.com/i/video.mp4/segment1_0_av.ts?null=0"
+        );
+        assert_eq!(
+            playlist.segments[1].uri.as_str(),
+            "https://cdn.example.com/segment2_0_av.ts"
+        );
+        assert!((playlist.segments[2].duration - 4.48).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn parse_media_with_map() {
+        let content = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:6.0,\nsegment1.m4s\n#EXT-X-ENDLIST\n";
+        let playlist = parse_media(&base(), content).unwrap().unwrap();
+        assert_eq!(
+            playlist.map.unwrap().as_str(),
+            "https://example.com/i/video.mp4/init.mp4"
+        );
+    }
+
+    #[test]
+    fn parse_media_unsupported() {
+        let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nindex_0_av.m3u8\n";
+        assert_eq!(parse_media(&base(), master).unwrap(), None);
+        let encrypted = "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\"\n#EXTINF:10,\na.ts\n";
+        assert_eq!(parse_media(&base(), encrypted).unwrap(), None);
+    }
+
+    #[test]
+    fn local_playlist_works() {
+        let playlist = parse_media(&base(), MEDIA).unwrap().unwrap();
+        let names = ["a.ts", "b.ts", "c.ts"].map(String::from);
+        assert_eq!(
+            local_playlist(&playlist, None, &names),
+            "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-TARGETDURATION:10
+#EXTINF:10.000,
+a.ts
+#EXTINF:10.000,
+b.ts
+#EXTINF:4.480,
+c.ts
+#EXT-X-ENDLIST
+"
+        );
+    }
+
+    #[test]
+    fn work_name_is_stable() {
+        assert_eq!(work_name(&base()), "hls-147799b01b2d3d15");
+    }
+
+    #[test]
+    fn abandoned_work_is_removed() {
+        let work = tempfile::tempdir().unwrap();
+        let old = work.path().join("hls-old");
+        let recent = work.path().join("hls-recent");
+        std::fs::create_dir(&old).unwrap();
+        std::fs::create_dir(&recent).unwrap();
+        let now = SystemTime::now();
+        std::fs::File::open(&old)
+            .unwrap()
+            .set_modified(now - ABANDONED_AFTER - Duration::from_hours(1))
+            .unwrap();
+
+        remove_abandoned(work.path(), now);
+        assert!(!old.exists());
+        assert!(recent.exists());
+    }
+
+    #[test]
+    fn extension_works() {
+        let url = Url::parse("https://example.com/segment1_0_av.ts?null=0").unwrap();
+        assert_eq!(extension(&url, ".bin"), ".ts");
+        let url = Url::parse("https://example.com/segment").unwrap();
+        assert_eq!(extension(&url, ".bin"), ".bin");
+    }
+}
@@ -1,35 +1,49 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
 
 use anyhow::Context as _;
 use frankenstein::TelegramApi as _;
 use frankenstein::client_ureq::Bot;
-use frankenstein::input_media::{InputMediaVideo, MediaGroupInputMedia};
+use frankenstein::input_media::{InputMedia, InputMediaVideo, MediaGroupInputMedia};
 use frankenstein::methods::{
-    EditMessageCaptionParams, SendMediaGroupParams, SendMessageParams, SendPhotoParams,
-    SendVideoParams,
+    EditMessageCaptionParams, EditMessageMediaParams, SendMediaGroupParams, SendMessageParams,
+    SendPhotoParams, SendVideoParams,
 };
+use frankenstein::types::ReplyParameters;
+use retry::retry;
+use tempfile::{NamedTempFile, TempDir};
 use url::Url;
 
-use crate::ffmpeg::{VideoStats, extract_video_thumbnail};
+use crate::config::{Oversize, TelegramConfig};
+use crate::ffmpeg::{self, extract_video_thumbnail};
+use crate::ffprobe::VideoStats;
 use crate::image::resize_to_tg_thumbnail;
-
-#[cfg(not(debug_assertions))]
-const PUBLIC_CHANNEL: i64 = -1_001_155_474_248;
-#[cfg(not(debug_assertions))]
-const META_CHANNEL: i64 = -1_001_214_301_516;
-
-#[cfg(debug_assertions)]
-const PUBLIC_CHANNEL: i64 = -1_001_149_205_144;
-#[cfg(debug_assertions)]
-const META_CHANNEL: i64 = -1_001_149_205_144;
+use crate::publisher::{Files, Publisher, Receipt, Upgrade};
+use crate::scrape::Scraperesult;
+use crate::{format_duration, format_filesize};
 
 pub struct Telegram {
     bot: Bot,
+    public_channel: i64,
+    meta_channel: i64,
+    upload_limit_megabytes: u64,
+    oversize: Oversize,
+    /// Meta channel message of the videos in progress by tracker id
+    meta_messages: Mutex<HashMap<String, MetaMessage>>,
+}
+
+struct MetaMessage {
+    id: i32,
+    caption: String,
 }
 
 impl Telegram {
-    pub fn new() -> Self {
-        let bot_token = std::env::var("BOT_TOKEN").expect("set BOT_TOKEN via environment variable");
+    pub fn new(config: &TelegramConfig) -> anyhow::Result<Self> {
+        let bot_token =
+            std::env::var("BOT_TOKEN").context("set BOT_TOKEN via environment variable")?;
 
         let bot = std::env::var("TELEGRAM_API_ROOT").map_or_else(
             |_| {
@@ -42,20 +56,27 @@ impl Telegram {
             },
         );
 
-        let me = bot.get_me().expect("Telegram get_me failed");
+        let me = bot.get_me().context("Telegram get_me failed")?;
         println!(
             "Telegram acts as @{}",
-            me.result.username.expect("Bot has no username")
+            me.result.username.context("Bot has no username")?
         );
 
-        Self { bot }
+        Ok(Self {
+            bot,
+            public_channel: config.public_channel,
+            meta_channel: config.meta_channel,
+            upload_limit_megabytes: config.upload_limit_megabytes,
+            oversize: config.oversize,
+            meta_messages: Mutex::new(HashMap::new()),
+        })
     }
 
     pub fn send_err(&self, text: &str) {
         self.bot
             .send_message(
                 &SendMessageParams::builder()
-                    .chat_id(META_CHANNEL)
+                    .chat_id(self.meta_channel)
                     .text(text)
                     .build(),
             )
@@ -67,7 +88,7 @@ impl Telegram {
             .bot
             .send_photo(
                 &SendPhotoParams::builder()
-                    .chat_id(META_CHANNEL)
+                    .chat_id(self.meta_channel)
                     .disable_notification(true)
                     .caption(text)
                     .photo(img.to_string())
@@ -83,7 +104,7 @@ impl Telegram {
         self.bot
             .edit_message_caption(
                 &EditMessageCaptionParams::builder()
-                    .chat_id(META_CHANNEL)
+                    .chat_id(self.meta_channel)
                     .message_id(msg_id)
                     .caption(text)
                     .build(),
@@ -92,67 +113,416 @@ impl Telegram {
         Ok(())
     }
 
-    pub fn send_public_result(
+    /// The videos of a post in order: the normal video with caption and cover first, then the variants like sign language.
+    /// Videos above the upload limit are split or re-encoded.
+    /// The fitted videos are returned as well as they have to stay alive until uploaded.
+    fn public_media<'a>(
         &self,
         caption: &str,
         cover: &Path,
         thumbnail: &Path,
-        normal: &Path,
-        sl: Option<&Path>,
-    ) -> anyhow::Result<()> {
-        if let Some(sl) = sl {
-            let sl_big_thumbnail = extract_video_thumbnail(sl)?;
-            let sl_tg_thumbnail = resize_to_tg_thumbnail(sl_big_thumbnail.path())?;
-            let media = vec![
-                build_media_group_video(normal, caption, Some(cover), thumbnail)?,
-                build_media_group_video(sl, "", None, sl_tg_thumbnail.path())?,
-            ];
-            self.bot
+        normal: &'a Path,
+        variants: &[&'a Path],
+    ) -> anyhow::Result<(Vec<Fitted<'a>>, Vec<InputMediaVideo>)> {
+        let normal = self.fit_upload_limit(normal)?;
+        let variants = variants
+            .iter()
+            .map(|variant| self.fit_upload_limit(variant))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut thumbnails = Vec::with_capacity(variants.len());
+        for variant in &variants {
+            let big_thumbnail = extract_video_thumbnail(variant.paths()[0])?;
+            thumbnails.push(resize_to_tg_thumbnail(big_thumbnail.path())?);
+        }
+
+        let mut media = Vec::new();
+        let parts = normal.paths();
+        for (index, part) in parts.iter().enumerate() {
+            media.push(build_video(
+                part,
+                &part_caption(caption, index, parts.len()),
+                (index == 0).then_some(cover),
+                thumbnail,
+            )?);
+        }
+        for (variant, thumbnail) in variants.iter().zip(&thumbnails) {
+            let parts = variant.paths();
+            for (index, part) in parts.iter().enumerate() {
+                media.push(build_video(
+                    part,
+                    &part_caption("", index, parts.len()),
+                    None,
+                    thumbnail.path(),
+                )?);
+            }
+        }
+        // The thumbnails of the variants are referenced by path until uploaded
+        let fitted = std::iter::once(normal)
+            .chain(variants)
+            .chain(thumbnails.into_iter().map(Fitted::Reencoded))
+            .collect();
+        Ok((fitted, media))
+    }
+
+    /// Post the videos, a single one as video and more as media groups.
+    /// Returns the ids of the posted messages.
+    fn send_public_result(
+        &self,
+        media: Vec<InputMediaVideo>,
+        reply_to: Option<i32>,
+    ) -> anyhow::Result<Vec<i32>> {
+        let reply_parameters =
+            reply_to.map(|message_id| ReplyParameters::builder().message_id(message_id).build());
+        if let [video] = media.as_slice() {
+            let video = video.clone();
+            let message = self
+                .bot
+                .send_video(
+                    &SendVideoParams::builder()
+                        .supports_streaming(true)
+                        .chat_id(self.public_channel)
+                        .video(video.media)
+                        .maybe_caption(video.caption)
+                        .maybe_cover(video.cover)
+                        .maybe_thumbnail(video.thumbnail)
+                        .maybe_duration(video.duration)
+                        .maybe_width(video.width)
+                        .maybe_height(video.height)
+                        .maybe_reply_parameters(reply_parameters)
+                        .build(),
+                )
+                .context("Telegram::send_video")?
+                .result;
+            return Ok(vec![message.message_id]);
+        }
+
+        let mut message_ids = Vec::new();
+        for group in media_groups(media) {
+            let messages = self
+                .bot
                 .send_media_group(
                     &SendMediaGroupParams::builder()
-                        .chat_id(PUBLIC_CHANNEL)
-                        .media(media)
+                        .chat_id(self.public_channel)
+                        .media(group.into_iter().map(MediaGroupInputMedia::Video).collect())
+                        .maybe_reply_parameters(reply_parameters.clone())
                         .build(),
                 )
-                .context("Telegram::send_media_group")?;
-        } else {
-            let stats = VideoStats::load(normal)?;
+                .context("Telegram::send_media_group")?
+                .result;
+            message_ids.extend(messages.iter().map(|message| message.message_id));
+        }
+        Ok(message_ids)
+    }
+
+    /// Replace the videos of the outdated post in place. Deleting is not possible for posts older than 48 hours.
+    /// Videos which do not fit into the outdated post are posted as reply to it.
+    /// Returns the ids of the edited and posted messages.
+    fn edit_previous(
+        &self,
+        previous: &[i32],
+        mut media: Vec<InputMediaVideo>,
+    ) -> anyhow::Result<Vec<i32>> {
+        let additional = media.split_off(previous.len().min(media.len()));
+        let mut message_ids = Vec::with_capacity(previous.len() + additional.len());
+        for (message_id, video) in previous.iter().zip(media) {
             self.bot
-                .send_video(
-                    &SendVideoParams::builder()
-                        .supports_streaming(true)
-                        .chat_id(PUBLIC_CHANNEL)
-                        .video(normal.to_path_buf())
-                        .caption(caption)
-                        .cover(cover.to_path_buf())
-                        .thumbnail(thumbnail.to_path_buf())
-                        .duration(stats.duration)
-                        .width(stats.width)
-                        .height(stats.height)
+                .edit_message_media(
+                    &EditMessageMediaParams::builder()
+                        .chat_id(self.public_channel)
+                        .message_id(*message_id)
+                        .media(InputMedia::Video(video))
                         .build(),
                 )
-                .context("Telegram::send_video")?;
+                .with_context(|| format!("Telegram::edit_message_media {message_id}"))?;
+            message_ids.push(*message_id);
         }
+        if !additional.is_empty() {
+            message_ids.extend(self.send_public_result(additional, previous.first().copied())?);
+        }
+        Ok(message_ids)
+    }
+
+    /// Split or re-encode the video when it is above the upload limit
+    fn fit_upload_limit<'a>(&self, video: &'a Path) -> anyhow::Result<Fitted<'a>> {
+        let limit = self.upload_limit_megabytes * 1024 * 1024;
+        let size = video.metadata()?.len();
+        if size <= limit {
+            return Ok(Fitted::Original(video));
+        }
+        let fitted = match self.oversize {
+            Oversize::Split => {
+                // Cuts happen at keyframes, leave some room for that
+                let parts = size.div_ceil(limit * 9 / 10);
+                println!(
+                    "{} is above the upload limit, split into {parts} parts",
+                    format_filesize(size)
+                );
+                let (directory, paths) = ffmpeg::split(video, parts.try_into()?)?;
+                Fitted::Split {
+                    _directory: directory,
+                    paths,
+                }
+            }
+            Oversize::Reencode => {
+                println!(
+                    "{} is above the upload limit, re-encode",
+                    format_filesize(size)
+                );
+                Fitted::Reencoded(ffmpeg::shrink(
+                    video,
+                    self.upload_limit_megabytes * 95 / 100,
+                )?)
+            }
+        };
+        for path in fitted.paths() {
+            let size = path.metadata()?.len();
+            anyhow::ensure!(
+                size <= limit,
+                "{} is still above the upload limit of {} MB",
+                format_filesize(size),
+                self.upload_limit_megabytes
+            );
+        }
+        Ok(fitted)
+    }
+}
+
+impl Publisher for Telegram {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn started(&self, video: &Scraperesult) -> anyhow::Result<()> {
+        let caption = video.caption();
+        let id = self.send_begin(&video.img, &caption)?;
+        self.meta_messages.lock().unwrap().insert(
+            video.media.tracker_data.id.clone(),
+            MetaMessage { id, caption },
+        );
         Ok(())
     }
+
+    fn progress(&self, video: &Scraperesult, status: &str) -> anyhow::Result<()> {
+        let caption = format!("{}\n\n{status}", video.caption());
+        let Some(id) = self.set_meta_caption(video, &caption) else {
+            return Ok(());
+        };
+        retry(retry::delay::Fixed::from_millis(60_000).take(2), || {
+            self.update_meta(id, &caption)
+        })
+        .map_err(anyhow::Error::msg)
+    }
+
+    fn encoding_progress(&self, video: &Scraperesult, status: &str) {
+        let meta_messages = self.meta_messages.lock().unwrap();
+        let Some(meta) = meta_messages.get(&video.media.tracker_data.id) else {
+            return;
+        };
+        let id = meta.id;
+        let caption = format!("{}\n\n{status}", meta.caption);
+        drop(meta_messages);
+        if let Err(error) = self.update_meta(id, &caption) {
+            println!("encoding progress not shown: {error:#}");
+        }
+    }
+
+    fn finished(&self, video: &Scraperesult, files: &Files) -> anyhow::Result<Receipt> {
+        let start = Instant::now();
+        let variants = [files.sl, files.ad]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let (_fitted, media) = self.public_media(
+            &video.caption(),
+            files.cover,
+            files.thumbnail,
+            files.normal,
+            &variants,
+        )?;
+        let (public_messages, upgrade) = match files.upgrade {
+            Some(upgrade) => {
+                let (messages, note) = self.replace_previous(upgrade, media)?;
+                (messages, Some(note))
+            }
+            None => (self.send_public_result(media, None)?, None),
+        };
+        let upload_took = start.elapsed();
+        println!("upload   took {}", format_duration(upload_took));
+
+        let meta = self
+            .meta_messages
+            .lock()
+            .unwrap()
+            .remove(&video.media.tracker_data.id);
+        let meta_message = if let Some(MetaMessage { id, mut caption }) = meta {
+            writeln!(caption, "upload took {}", format_duration(upload_took)).unwrap();
+            if let Some(upgrade) = &upgrade {
+                writeln!(caption, "\n{upgrade}").unwrap();
+            }
+            retry(retry::delay::Fixed::from_millis(60_000).take(2), || {
+                self.update_meta(id, &caption)
+            })
+            .map_err(anyhow::Error::msg)?;
+            Some(id)
+        } else {
+            None
+        };
+        Ok(Receipt {
+            public_messages,
+            meta_message,
+            failures: Vec::new(),
+        })
+    }
+
+    fn failed(&self, video: Option<&Scraperesult>, error: &str) {
+        let text = video.map_or_else(
+            || format!("ERROR {error}"),
+            |video| {
+                self.meta_messages
+                    .lock()
+                    .unwrap()
+                    .remove(&video.media.tracker_data.id);
+                let title = &video.media.tracker_data.title;
+                format!("ERROR Failed to download {title:?}: {error}")
+            },
+        );
+        self.send_err(&text);
+    }
+}
+
+impl Telegram {
+    /// Replace the outdated post so subscribers do not see duplicates.
+    /// When editing fails the new version is posted as reply to the outdated one.
+    /// Returns the message ids and the note for the meta channel.
+    fn replace_previous(
+        &self,
+        upgrade: &Upgrade,
+        media: Vec<InputMediaVideo>,
+    ) -> anyhow::Result<(Vec<i32>, String)> {
+        let previous = &upgrade.previous.public_messages;
+        if previous.is_empty() {
+            let messages = self.send_public_result(media, None)?;
+            return Ok((
+                messages,
+                format!("{}, alte Nachrichten unbekannt", upgrade.note),
+            ));
+        }
+        match self.edit_previous(previous, media.clone()) {
+            Ok(messages) => Ok((
+                messages,
+                format!(
+                    "{}, {} alte Nachrichten ersetzt",
+                    upgrade.note,
+                    previous.len()
+                ),
+            )),
+            Err(error) => {
+                eprintln!("failed to edit the previous post: {error:#}");
+                let messages = self.send_public_result(media, previous.first().copied())?;
+                Ok((
+                    messages,
+                    format!(
+                        "{}, alte Nachrichten {previous:?} nicht ersetzt, als Antwort gepostet: {error}",
+                        upgrade.note
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Remember the new caption of the meta message. Returns the message id when there is one.
+    fn set_meta_caption(&self, video: &Scraperesult, caption: &str) -> Option<i32> {
+        let mut meta_messages = self.meta_messages.lock().unwrap();
+        let meta = meta_messages.get_mut(&video.media.tracker_data.id)?;
+        caption.clone_into(&mut meta.caption);
+        let id = meta.id;
+        drop(meta_messages);
+        Some(id)
+    }
+}
+
+/// A video within the upload limit. Keeps the temporary files alive while uploading.
+enum Fitted<'a> {
+    Original(&'a Path),
+    Reencoded(NamedTempFile),
+    Split {
+        /// Deletes the parts when dropped
+        _directory: TempDir,
+        paths: Vec<PathBuf>,
+    },
 }
 
-fn build_media_group_video(
+impl Fitted<'_> {
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Self::Original(path) => vec![path],
+            Self::Reencoded(file) => vec![file.path()],
+            Self::Split { paths, .. } => paths.iter().map(PathBuf::as_path).collect(),
+        }
+    }
+}
+
+/// Numbered caption of a part. Unchanged when there is only one part.
+fn part_caption(caption: &str, index: usize, total: usize) -> String {
+    if total <= 1 {
+        caption.to_owned()
+    } else if caption.is_empty() {
+        format!("Teil {}/{total}", index + 1)
+    } else {
+        format!("{caption}\nTeil {}/{total}", index + 1)
+    }
+}
+
+/// Split into media groups of at most 10 items with at least 2 items each
+fn media_groups<T>(items: Vec<T>) -> Vec<Vec<T>> {
+    const MAX: usize = 10;
+    let groups = items.len().div_ceil(MAX).max(1);
+    let size = items.len().div_ceil(groups);
+    let mut items = items.into_iter().peekable();
+    let mut result = Vec::with_capacity(groups);
+    while items.peek().is_some() {
+        result.push(items.by_ref().take(size).collect());
+    }
+    result
+}
+
+fn build_video(
     media: &Path,
     caption: &str,
     cover: Option<&Path>,
     thumbnail: &Path,
-) -> anyhow::Result<MediaGroupInputMedia> {
+) -> anyhow::Result<InputMediaVideo> {
     let stats = VideoStats::load(media)?;
-    let video = InputMediaVideo::builder()
+    Ok(InputMediaVideo::builder()
         .supports_streaming(true)
         .media(media.to_path_buf())
         .caption(caption)
         .maybe_cover(cover.map(Path::to_path_buf))
         .thumbnail(thumbnail.to_path_buf())
-        .duration(stats.duration)
-        .width(stats.width)
-        .height(stats.height)
-        .build();
-    Ok(MediaGroupInputMedia::Video(video))
+        .duration(stats.duration_seconds())
+        .width(stats.width())
+        .height(stats.height())
+        .build())
+}
+
+#[test]
+fn part_caption_works() {
+    assert_eq!(part_caption("Maus", 0, 1), "Maus");
+    assert_eq!(part_caption("Maus", 0, 2), "Maus\nTeil 1/2");
+    assert_eq!(part_caption("", 1, 2), "Teil 2/2");
+}
+
+#[test]
+fn media_groups_works() {
+    let sizes = |amount: usize| {
+        media_groups((0..amount).collect())
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(sizes(2), [2]);
+    assert_eq!(sizes(10), [10]);
+    assert_eq!(sizes(11), [6, 5]);
+    assert_eq!(sizes(21), [7, 7, 7]);
 }
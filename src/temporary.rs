@@ -1,4 +1,4 @@
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempDir};
 
 pub fn get_tempfile(suffix: &'static str) -> std::io::Result<NamedTempFile> {
     tempfile::Builder::new()
@@ -6,3 +6,7 @@ pub fn get_tempfile(suffix: &'static str) -> std::io::Result<NamedTempFile> {
         .suffix(suffix)
         .tempfile()
 }
+
+pub fn get_tempdir() -> std::io::Result<TempDir> {
+    tempfile::Builder::new().prefix("wdr-maus-").tempdir()
+}
@@ -1,3 +1,4 @@
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
@@ -82,6 +83,53 @@ pub struct Captions {
         skip_serializing_if = "Option::is_none"
     )]
     pub srt: Option<Url>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_opt_url",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub vtt: Option<Url>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_opt_url",
+        skip_serializing_if = "Option::is_none"
+    )]
+    /// EBU-TT (TTML)
+    pub xml: Option<Url>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptionFormat {
+    Srt,
+    Vtt,
+    EbuTt,
+}
+
+impl CaptionFormat {
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Srt => ".srt",
+            Self::Vtt => ".vtt",
+            Self::EbuTt => ".ttml",
+        }
+    }
+}
+
+impl Captions {
+    /// All caption files in order of preference.
+    /// Sometimes WDR replaces a file with one ending in `deleted` which is skipped.
+    pub fn available(&self) -> impl Iterator<Item = (CaptionFormat, &Url)> {
+        [
+            (CaptionFormat::Srt, self.srt.as_ref()),
+            (CaptionFormat::Vtt, self.vtt.as_ref()),
+            (CaptionFormat::EbuTt, self.xml.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(format, url)| Some((format, url?)))
+        .filter(|(_, url)| !url.path().ends_with("deleted"))
+    }
 }
 
 pub fn deserialize_url<'de, D>(deserializer: D) -> Result<Url, D::Error>
@@ -105,15 +153,45 @@ where
     Ok(Some(url))
 }
 
+impl TrackerData {
+    /// Parse the date from the air time like `21.11.2021 09:30`
+    pub fn air_date(&self) -> Option<NaiveDate> {
+        let air_time = self.air_time.as_deref()?;
+        let date = air_time.split_whitespace().next()?;
+        NaiveDate::parse_from_str(date, "%d.%m.%Y").ok()
+    }
+}
+
+#[test]
+fn air_date_works() {
+    let mut tracker_data = TrackerData {
+        id: "a".into(),
+        air_time: Some("21.11.2021 09:30".into()),
+        title: "42".into(),
+    };
+    assert_eq!(
+        tracker_data.air_date(),
+        NaiveDate::from_ymd_opt(2021, 11, 21)
+    );
+    tracker_data.air_time = Some("unknown".into());
+    assert_eq!(tracker_data.air_date(), None);
+    tracker_data.air_time = None;
+    assert_eq!(tracker_data.air_date(), None);
+}
+
 impl MediaResources {
     pub const fn score(&self) -> usize {
         let mut score = 0;
+        // Only SRT as the history of earlier versions does not know about other formats
         if self.captions_hash.srt.is_some() {
             score += 1;
         }
         if self.dflt.sl_video.is_some() {
             score += 1;
         }
+        if self.dflt.ad_video.is_some() {
+            score += 1;
+        }
         score
     }
 
@@ -132,6 +210,14 @@ impl MediaResources {
             self.dflt.sl_video.as_ref()
         }
     }
+
+    pub const fn get_ad_video(&self) -> Option<&Url> {
+        if matches!(self.alt.media_format, MediaFormat::Mp4) {
+            self.alt.ad_video.as_ref()
+        } else {
+            self.dflt.ad_video.as_ref()
+        }
+    }
 }
 
 #[test]
@@ -212,6 +298,49 @@ fn sendung_example() {
     // todo!();
 }
 
+#[test]
+fn sendung_with_all_variants() {
+    let json = r#"{
+    "mediaResource": {
+        "alt": {
+            "adVideoURL": "//wdradaptiv-vh.akamaihd.net/i/medp/ondemand/deChAt/fsk0/258/2580812/ad.mp4.csmil/master.m3u8",
+            "mediaFormat": "hls",
+            "slVideoURL": "//wdradaptiv-vh.akamaihd.net/i/medp/ondemand/deChAt/fsk0/258/2580812/sl.mp4.csmil/master.m3u8",
+            "videoURL": "//wdradaptiv-vh.akamaihd.net/i/medp/ondemand/deChAt/fsk0/258/2580812/video.mp4.csmil/master.m3u8"
+        },
+        "captionsHash": {
+            "srt": "//wdrmedien-a.akamaihd.net/medp/ondemand/deChAt/fsk0/258/2580812/2580812_40254488.srt",
+            "vtt": "//wdrmedien-a.akamaihd.net/medp/ondemand/deChAt/fsk0/258/2580812/2580812_40254489.vtt",
+            "xml": "//wdrmedien-a.akamaihd.net/medp/ondemand/deChAt/fsk0/258/2580812/2580812_40254487.xml"
+        },
+        "dflt": {
+            "adVideoURL": "//wdradaptiv-vh.akamaihd.net/i/medp/ondemand/deChAt/fsk0/258/2580812/ad.mp4.csmil/master.m3u8",
+            "mediaFormat": "hls",
+            "slVideoURL": "//wdradaptiv-vh.akamaihd.net/i/medp/ondemand/deChAt/fsk0/258/2580812/sl.mp4.csmil/master.m3u8",
+            "videoURL": "//wdradaptiv-vh.akamaihd.net/i/medp/ondemand/deChAt/fsk0/258/2580812/video.mp4.csmil/master.m3u8"
+        }
+    },
+    "trackerData": {
+        "trackerClipAirTime": "21.11.2021 09:30",
+        "trackerClipId": "mdb-2580812",
+        "trackerClipTitle": "Die Sendung vom 21.11.2021"
+    }
+}"#;
+    let media = serde_json::from_str::<WdrMedia>(json).unwrap();
+    assert!(media.media_resource.get_ad_video().is_some());
+    assert_eq!(media.media_resource.score(), 3);
+    let formats = media
+        .media_resource
+        .captions_hash
+        .available()
+        .map(|(format, _)| format)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        formats,
+        [CaptionFormat::Srt, CaptionFormat::Vtt, CaptionFormat::EbuTt]
+    );
+}
+
 #[test]
 fn kuh_lena() {
     let json = r#"{
@@ -240,3 +369,17 @@ fn kuh_lena() {
     dbg!(media);
     // todo!();
 }
+
+#[test]
+fn deleted_captions_are_skipped() {
+    let captions = Captions {
+        srt: Url::parse("https://wdrmedien-a.akamaihd.net/medp/2580812_40254488.srt/deleted").ok(),
+        vtt: None,
+        xml: Url::parse("https://wdrmedien-a.akamaihd.net/medp/2580812_40254487.xml").ok(),
+    };
+    let formats = captions
+        .available()
+        .map(|(format, _)| format)
+        .collect::<Vec<_>>();
+    assert_eq!(formats, [CaptionFormat::EbuTt]);
+}